@@ -0,0 +1,277 @@
+//! The interface between the debugger and whatever executes the machine:
+//! an xsm child process, a recorded trace, ...
+//! Breakpoints, history and traces are handled by `XSM` on top of it.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+use std::time::Duration;
+
+use crate::parse::ParseError;
+use crate::word::XSMWord;
+use crate::xsm::{Mode, XSMRegs};
+
+/// What the machine reports each time it stops in debug mode.
+#[derive(Debug, Default)]
+pub struct Stop {
+    /// Lines printed by OUT since the last stop
+    pub output: Vec<String>,
+    /// The watched physical address written to, if that is why the machine stopped
+    pub watch_addr: Option<usize>,
+    /// Set once the machine has come to an end
+    pub end: Option<End>,
+    /// None if the machine ended or the status could not be read
+    pub status: Option<Status>,
+    /// Number of instructions executed since the last stop
+    pub executed: usize,
+    /// True if the machine came to an end within a step of several
+    /// instructions without telling after how many;
+    /// `executed` is then the number asked for
+    pub executed_unknown: bool,
+    /// True if the last instruction executed was IN,
+    /// which waits for a line of console input
+    pub console_read: bool,
+    /// Console input recorded with the instructions executed,
+    /// with the step count it was written at, for backends replaying it
+    pub input: Vec<(usize, String)>,
+    /// Set if something went wrong on the way to the stop,
+    /// such as a status that could not be understood
+    pub error: Option<BackendError>,
+}
+
+/// How the machine came to an end.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum End {
+    /// The machine executed HALT
+    Halted,
+    /// xsm exited without the machine halting, or died of a fault
+    Crashed(ExitStatus),
+    /// xsm was killed from outside, by a signal such as SIGKILL or SIGTERM
+    Killed(ExitStatus),
+}
+
+impl End {
+    /// How xsm ended without halting the machine,
+    /// told apart by the signal it died of, if any.
+    pub fn of_exit(status: ExitStatus) -> End {
+        match status.signal() {
+            Some(libc::SIGKILL) | Some(libc::SIGTERM) | Some(libc::SIGINT) | Some(libc::SIGHUP) => {
+                End::Killed(status)
+            }
+            _ => End::Crashed(status),
+        }
+    }
+}
+
+impl fmt::Display for End {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            End::Halted => write!(f, "Machine halted"),
+            End::Crashed(status) => write!(f, "xsm crashed ({})", status),
+            End::Killed(status) => write!(f, "xsm was killed ({})", status),
+        }
+    }
+}
+
+/// The status lines shown while the machine is in debug mode.
+#[derive(Debug, Clone)]
+pub struct Status {
+    pub text: String,
+    pub mode: Mode,
+    /// Address of the next instruction
+    pub ip: usize,
+    pub next_instr: String,
+}
+
+/// Why the machine could not be started or talked to.
+#[derive(Debug)]
+pub enum BackendError {
+    /// No program was given to run
+    NoCommand,
+    /// The program to run does not exist
+    NotFound {
+        program: String,
+    },
+    /// A pseudo-terminal for xsm could not be opened
+    Pty(io::Error),
+    /// The temporary working directory for xsm could not be set up
+    WorkDir(io::Error),
+    /// The command could not be started
+    Launch {
+        command: String,
+        source: io::Error,
+    },
+    /// xsm exited before entering debug mode,
+    /// usually because of a wrong command line
+    NotInDebugMode {
+        command: String,
+        status: ExitStatus,
+        /// What xsm printed before exiting, such as a usage message
+        output: Vec<String>,
+    },
+    /// xsm exited while being debugged
+    Exited(ExitStatus),
+    /// How xsm exited could not be found out
+    Wait(io::Error),
+    /// A command could not be written to xsm
+    Send(io::Error),
+    /// xsm could not be sent the signal to stop running
+    Interrupt(io::Error),
+    /// xsm did not finish responding to a command in time
+    Timeout {
+        command: String,
+        timeout: Duration,
+    },
+    /// The output of xsm could not be understood
+    Parse(ParseError),
+    /// The `mem` file written by xsm to its work dir could not be read
+    MemFile(io::Error),
+    Trace {
+        path: PathBuf,
+        source: io::Error,
+    },
+    Disk {
+        path: PathBuf,
+        source: io::Error,
+    },
+    /// A physical page past the end of memory
+    PageOutOfRange(usize),
+}
+
+impl fmt::Display for BackendError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BackendError::NoCommand => write!(f, "No xsm command line given"),
+            BackendError::NotFound { program } => write!(
+                f,
+                "'{}' not found, please give the path to the xsm binary",
+                program
+            ),
+            BackendError::Pty(e) => write!(f, "Failed to open a terminal for xsm: {}", e),
+            BackendError::WorkDir(e) => {
+                write!(f, "Failed to set up a working directory for xsm: {}", e)
+            }
+            BackendError::Launch { command, source } => {
+                write!(f, "Failed to launch '{}': {}", command.trim(), source)
+            }
+            BackendError::NotInDebugMode {
+                command,
+                status,
+                output,
+            } => {
+                write!(
+                    f,
+                    "'{}' exited ({}) without entering debug mode, please check the command line",
+                    command.trim(),
+                    status
+                )?;
+                for line in output {
+                    write!(f, "\n{}", line.trim_end())?;
+                }
+                Ok(())
+            }
+            BackendError::Exited(status) => write!(f, "xsm exited ({})", status),
+            BackendError::Wait(e) => write!(f, "Failed to wait for xsm to exit: {}", e),
+            BackendError::Send(e) => write!(f, "Failed to send command to xsm: {}", e),
+            BackendError::Interrupt(e) => write!(f, "Failed to interrupt xsm: {}", e),
+            BackendError::Timeout { command, timeout } => write!(
+                f,
+                "xsm did not respond to '{}' within {:.1} s",
+                command,
+                timeout.as_secs_f64()
+            ),
+            BackendError::Parse(e) => write!(f, "{}", e),
+            BackendError::MemFile(e) => write!(f, "Failed to read mem file: {}", e),
+            BackendError::Trace { path, source } => {
+                write!(f, "Failed to open trace {}: {}", path.display(), source)
+            }
+            BackendError::Disk { path, source } => {
+                write!(f, "Failed to access disk {}: {}", path.display(), source)
+            }
+            BackendError::PageOutOfRange(page) => write!(f, "Page {} is out of memory", page),
+        }
+    }
+}
+
+impl Error for BackendError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            BackendError::Launch { source, .. }
+            | BackendError::Trace { source, .. }
+            | BackendError::Disk { source, .. }
+            | BackendError::Pty(source)
+            | BackendError::WorkDir(source)
+            | BackendError::Send(source)
+            | BackendError::Interrupt(source)
+            | BackendError::Wait(source)
+            | BackendError::MemFile(source) => Some(source),
+            BackendError::Parse(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<ParseError> for BackendError {
+    fn from(e: ParseError) -> Self {
+        BackendError::Parse(e)
+    }
+}
+
+pub trait DebugBackend: Send {
+    /// What the machine reported on starting, before any instruction was executed.
+    /// Called once, right after the backend is created.
+    fn initial_stop(&mut self) -> Stop;
+
+    /// Executes n instructions, or fewer if the machine halts,
+    /// a watchpoint is triggered or console input is asked for:
+    /// right after an IN, and before an INI.
+    fn step(&mut self, n: usize) -> Result<Stop, BackendError>;
+
+    /// Lets the machine run until a BRKP instruction, a watchpoint or HALT.
+    /// Returns immediately; call `poll` until it stops.
+    fn run(&mut self) -> Result<(), BackendError>;
+
+    /// Returns the stop once the machine started by `run` has stopped,
+    /// without blocking.
+    fn poll(&mut self) -> Result<Option<Stop>, BackendError>;
+
+    /// Makes the machine started by `run` stop as soon as it can,
+    /// as on Ctrl+C in a terminal. `poll` then returns the stop.
+    fn interrupt(&mut self) -> Result<(), BackendError>;
+
+    fn read_regs(&mut self) -> Result<XSMRegs, BackendError>;
+
+    /// Returns the words of a physical page,
+    /// or nothing if the backend has no memory.
+    fn read_mem_page(&mut self, page: usize) -> Result<Vec<XSMWord>, BackendError>;
+
+    /// Makes the machine stop when the physical address is written to.
+    fn watch(&mut self, addr: usize) -> Result<(), BackendError>;
+
+    fn watch_clear(&mut self) -> Result<(), BackendError>;
+
+    /// Writes a line of console input, read by the IN instruction.
+    fn write_input(&mut self, line: &str) -> Result<(), BackendError>;
+
+    /// A fresh backend running the same program from the start.
+    fn restart(&self) -> Result<Box<dyn DebugBackend>, BackendError>;
+
+    /// False if memory cannot be read; code is then taken from `instr_at`
+    /// and watchpoints are not available.
+    fn has_memory(&self) -> bool {
+        true
+    }
+
+    /// The instruction at ip, for backends without memory.
+    fn instr_at(&self, _is_user: bool, _ip: usize) -> Option<&str> {
+        None
+    }
+
+    /// The trace file being viewed, for backends replaying one.
+    fn replay_path(&self) -> Option<&Path> {
+        None
+    }
+}
@@ -0,0 +1,264 @@
+//! A stand-in for xsm that speaks its debug mode protocol,
+//! used by the integration tests.
+//!
+//! Usage: mock_xsm [xsm options] <script.json>
+//!
+//! Instead of executing code, it walks through the machine states listed
+//! in the script, one per instruction:
+//! ```json
+//! { "states": [
+//!     { "mode": "K", "ip": 0, "instr": "MOV R0, 1" },
+//!     { "mode": "K", "ip": 2, "instr": "OUT", "regs": { "R0": "1" },
+//!       "output": ["hello"], "mem": { "1": { "0": "word" } },
+//!       "brkp": false, "delay_ms": 0 },
+//!     { "mode": "K", "ip": 4, "instr": "NOP", "exit": 3, "signal": 9 }
+//! ] }
+//! ```
+//! The first state is shown on start. `regs` and `mem` only list changes.
+//! `output` is printed on reaching a state, and `brkp` stops `continue` there.
+//! A state that writes to an address given to `watch` stops stepping there,
+//! reporting the address as xsm does.
+//! `delay_ms` makes reaching a state take that long, like a slow machine.
+//! `exit` and `signal` make the mock exit with that code or die of that
+//! signal on reaching a state, after printing its output.
+//! Stepping past the last state halts the machine.
+//! SIGINT stops `continue` at the state reached, as Ctrl+C does in xsm.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use serde::Deserialize;
+
+const REG_LINES: [&[&str]; 7] = [
+    &["R0", "R1", "R2", "R3", "R4"],
+    &["R5", "R6", "R7", "R8", "R9"],
+    &["R10", "R11", "R12", "R13", "R14"],
+    &["R15", "R16", "R17", "R18", "R19"],
+    &["P0", "P1", "P2", "P3"],
+    &["BP", "SP", "IP", "PTBR", "PTLR"],
+    &["EIP", "EC", "EPN", "EMA"],
+];
+
+/// Set by SIGINT, to stop `continue`.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_sigint(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+#[derive(Deserialize)]
+struct Script {
+    states: Vec<State>,
+}
+
+#[derive(Deserialize)]
+struct State {
+    mode: String,
+    ip: usize,
+    instr: String,
+    #[serde(default)]
+    regs: BTreeMap<String, String>,
+    #[serde(default)]
+    output: Vec<String>,
+    #[serde(default)]
+    mem: BTreeMap<usize, BTreeMap<usize, String>>,
+    #[serde(default)]
+    brkp: bool,
+    #[serde(default)]
+    delay_ms: u64,
+    exit: Option<i32>,
+    signal: Option<i32>,
+}
+
+struct Mock {
+    states: Vec<State>,
+    pos: usize,
+    regs: BTreeMap<String, String>,
+    mem: BTreeMap<usize, BTreeMap<usize, String>>,
+    watched: Vec<usize>,
+    /// True if reaching the current state wrote to a watched address
+    watch_hit: bool,
+}
+
+impl Mock {
+    /// Moves to the next state. Returns false past the last one.
+    fn advance(&mut self, out: &mut impl Write) -> io::Result<bool> {
+        self.pos += 1;
+        let state = match self.states.get(self.pos) {
+            Some(state) => state,
+            None => return Ok(false),
+        };
+        std::thread::sleep(std::time::Duration::from_millis(state.delay_ms));
+        for line in &state.output {
+            writeln!(out, "{}", line)?;
+        }
+        let watched = &self.watched;
+        let hits: Vec<usize> = state
+            .mem
+            .iter()
+            .flat_map(|(page, words)| words.keys().map(move |i| page * 512 + i))
+            .filter(|addr| watched.contains(addr))
+            .collect();
+        for addr in &hits {
+            writeln!(out, "Watchpoint at memory location {} triggered", addr)?;
+        }
+        self.watch_hit = !hits.is_empty();
+        out.flush()?;
+        if let Some(signal) = state.signal {
+            // SAFETY: raising a signal has no memory safety requirements.
+            unsafe { libc::raise(signal) };
+        }
+        if let Some(code) = state.exit {
+            std::process::exit(code);
+        }
+        self.apply(self.pos);
+        Ok(true)
+    }
+
+    fn apply(&mut self, pos: usize) {
+        let state = &self.states[pos];
+        self.regs
+            .extend(state.regs.iter().map(|(k, v)| (k.clone(), v.clone())));
+        self.regs.insert("IP".to_owned(), state.ip.to_string());
+        for (page, words) in &state.mem {
+            self.mem
+                .entry(*page)
+                .or_default()
+                .extend(words.iter().map(|(k, v)| (*k, v.clone())));
+        }
+    }
+
+    fn print_status(&self, out: &mut impl Write) -> io::Result<()> {
+        let state = &self.states[self.pos];
+        match self.pos.checked_sub(1).map(|i| &self.states[i]) {
+            Some(prev) => writeln!(
+                out,
+                "Previous instruction at IP = {}: {}",
+                prev.ip, prev.instr
+            )?,
+            None => writeln!(out, "Previous instruction at IP = 0: NOP")?,
+        }
+        let mode = if state.mode == "U" { "USER" } else { "KERNEL" };
+        writeln!(out, "Mode: {} \t PID: 0", mode)?;
+        writeln!(
+            out,
+            "Next instruction at IP = {}, Page No. = {}: {}",
+            state.ip,
+            state.ip / 512,
+            state.instr
+        )
+    }
+
+    fn print_regs(&self, out: &mut impl Write) -> io::Result<()> {
+        for names in REG_LINES.iter() {
+            for name in names.iter() {
+                let value = self.regs.get(*name).map(String::as_str).unwrap_or("0");
+                write!(out, "{}: {}\t", name, value)?;
+            }
+            writeln!(out)?;
+        }
+        Ok(())
+    }
+
+    fn write_mem(&self, page: usize) -> io::Result<()> {
+        let words = self.mem.get(&page);
+        let mut text = String::new();
+        for i in 0..512 {
+            let word = words.and_then(|w| w.get(&i)).map(String::as_str);
+            text.push_str(&format!("{}: {}\n", page * 512 + i, word.unwrap_or("")));
+        }
+        fs::write("mem", text)
+    }
+}
+
+fn halt(out: &mut impl Write) -> ! {
+    let _ = writeln!(out, "Machine is halting.");
+    let _ = out.flush();
+    std::process::exit(0);
+}
+
+fn main() -> io::Result<()> {
+    let path = match std::env::args().skip(1).rfind(|a| !a.starts_with("--")) {
+        Some(path) => path,
+        None => {
+            eprintln!("Usage: mock_xsm [xsm options] <script.json>");
+            std::process::exit(2);
+        }
+    };
+    let script: Script = serde_json::from_str(&fs::read_to_string(&path)?)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    if script.states.is_empty() {
+        eprintln!("The script has no states");
+        std::process::exit(2);
+    }
+    let mut mock = Mock {
+        states: script.states,
+        pos: 0,
+        regs: BTreeMap::new(),
+        mem: BTreeMap::new(),
+        watched: Vec::new(),
+        watch_hit: false,
+    };
+    mock.apply(0);
+    // SAFETY: the handler only stores to an atomic.
+    unsafe { libc::signal(libc::SIGINT, on_sigint as *const () as libc::sighandler_t) };
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    mock.print_status(&mut out)?;
+    write!(out, "debug> ")?;
+    out.flush()?;
+
+    let stdin = io::stdin();
+    for line in stdin.lock().lines() {
+        let line = line?;
+        let mut words = line.split_whitespace();
+        match words.next() {
+            Some("step") | Some("s") => {
+                let n: usize = words.next().and_then(|n| n.parse().ok()).unwrap_or(1);
+                for _ in 0..n {
+                    if !mock.advance(&mut out)? {
+                        halt(&mut out);
+                    }
+                    if mock.watch_hit {
+                        break;
+                    }
+                }
+                mock.print_status(&mut out)?;
+            }
+            Some("continue") | Some("c") => {
+                INTERRUPTED.store(false, Ordering::SeqCst);
+                loop {
+                    if !mock.advance(&mut out)? {
+                        halt(&mut out);
+                    }
+                    let state = &mock.states[mock.pos];
+                    if state.brkp || mock.watch_hit || INTERRUPTED.load(Ordering::SeqCst) {
+                        mock.print_status(&mut out)?;
+                        break;
+                    }
+                }
+            }
+            Some("reg") => mock.print_regs(&mut out)?,
+            Some("mem") => {
+                let page = words.next().and_then(|n| n.parse().ok()).unwrap_or(0);
+                mock.write_mem(page)?;
+                writeln!(out, "Written to file mem.")?;
+            }
+            Some("watch") => {
+                if let Some(addr) = words.next().and_then(|n| n.parse().ok()) {
+                    mock.watched.push(addr);
+                }
+            }
+            Some("watchclear") => mock.watched.clear(),
+            Some("exit") | Some("quit") => return Ok(()),
+            // Console input is read by IN, which does not prompt again.
+            _ => continue,
+        }
+        write!(out, "debug> ")?;
+        out.flush()?;
+    }
+    Ok(())
+}
@@ -0,0 +1,192 @@
+//! Decoding of XSM instructions from the text held in two memory words.
+
+use crate::expr::Reg;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+    Reg(Reg),
+    Int(i64),
+    Str(String),
+    /// `[Ri]`
+    MemReg(Reg),
+    /// `[n]`
+    MemInt(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArithOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CmpOp {
+    Lt,
+    Gt,
+    Eq,
+    Ne,
+    Ge,
+    Le,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Instr {
+    Mov(Operand, Operand),
+    Arith(ArithOp, Reg, Operand),
+    Inr(Reg),
+    Dcr(Reg),
+    Cmp(CmpOp, Reg, Operand),
+    Jz(Reg, Operand),
+    Jnz(Reg, Operand),
+    Jmp(Operand),
+    Push(Reg),
+    Pop(Reg),
+    Call(Operand),
+    Ret,
+    Int(usize),
+    Iret,
+    In,
+    Ini,
+    Out,
+    Load(Operand, Operand),
+    Loadi(Operand, Operand),
+    Store(Operand, Operand),
+    Backup,
+    Restore,
+    Strcmp(Reg, Reg),
+    Strcpy(Reg, Reg),
+    Halt,
+    Brkp,
+    Nop,
+}
+
+impl Instr {
+    /// Parses an instruction such as `MOV R0, [R1]`.
+    /// Returns None for anything that is not a valid instruction.
+    pub fn parse(text: &str) -> Option<Instr> {
+        let text = text.trim();
+        let (opcode, rest) = match text.find(char::is_whitespace) {
+            Some(i) => (&text[..i], &text[i..]),
+            None => (text, ""),
+        };
+        let args = split_args(rest)?;
+        let args: Vec<Operand> = args
+            .iter()
+            .map(|arg| parse_operand(arg))
+            .collect::<Option<_>>()?;
+        let reg = |i: usize| match args.get(i) {
+            Some(Operand::Reg(reg)) => Some(*reg),
+            _ => None,
+        };
+        let arg = |i: usize| args.get(i).cloned();
+        let arity = |n: usize| if args.len() == n { Some(()) } else { None };
+
+        let arith = |op| {
+            arity(2)?;
+            Some(Instr::Arith(op, reg(0)?, arg(1)?))
+        };
+        let cmp = |op| {
+            arity(2)?;
+            Some(Instr::Cmp(op, reg(0)?, arg(1)?))
+        };
+        match opcode.to_ascii_uppercase().as_str() {
+            "MOV" => {
+                arity(2)?;
+                let (dst, src) = (arg(0)?, arg(1)?);
+                let is_mem = |op: &Operand| matches!(op, Operand::MemReg(_) | Operand::MemInt(_));
+                match dst {
+                    Operand::Reg(_) => {}
+                    Operand::MemReg(_) | Operand::MemInt(_) if !is_mem(&src) => {}
+                    _ => return None,
+                }
+                Some(Instr::Mov(dst, src))
+            }
+            "ADD" => arith(ArithOp::Add),
+            "SUB" => arith(ArithOp::Sub),
+            "MUL" => arith(ArithOp::Mul),
+            "DIV" => arith(ArithOp::Div),
+            "MOD" => arith(ArithOp::Mod),
+            "INR" => arity(1).and_then(|_| Some(Instr::Inr(reg(0)?))),
+            "DCR" => arity(1).and_then(|_| Some(Instr::Dcr(reg(0)?))),
+            "LT" => cmp(CmpOp::Lt),
+            "GT" => cmp(CmpOp::Gt),
+            "EQ" => cmp(CmpOp::Eq),
+            "NE" => cmp(CmpOp::Ne),
+            "GE" => cmp(CmpOp::Ge),
+            "LE" => cmp(CmpOp::Le),
+            "JZ" => arity(2).and_then(|_| Some(Instr::Jz(reg(0)?, arg(1)?))),
+            "JNZ" => arity(2).and_then(|_| Some(Instr::Jnz(reg(0)?, arg(1)?))),
+            "JMP" => arity(1).and_then(|_| Some(Instr::Jmp(arg(0)?))),
+            "PUSH" => arity(1).and_then(|_| Some(Instr::Push(reg(0)?))),
+            "POP" => arity(1).and_then(|_| Some(Instr::Pop(reg(0)?))),
+            "CALL" => arity(1).and_then(|_| Some(Instr::Call(arg(0)?))),
+            "RET" => arity(0).map(|_| Instr::Ret),
+            "INT" => {
+                arity(1)?;
+                match arg(0)? {
+                    Operand::Int(n) if n >= 0 => Some(Instr::Int(n as usize)),
+                    _ => None,
+                }
+            }
+            "IRET" => arity(0).map(|_| Instr::Iret),
+            "IN" => arity(0).map(|_| Instr::In),
+            "INI" => arity(0).map(|_| Instr::Ini),
+            "OUT" => arity(0).map(|_| Instr::Out),
+            "LOAD" => arity(2).and_then(|_| Some(Instr::Load(arg(0)?, arg(1)?))),
+            "LOADI" => arity(2).and_then(|_| Some(Instr::Loadi(arg(0)?, arg(1)?))),
+            "STORE" => arity(2).and_then(|_| Some(Instr::Store(arg(0)?, arg(1)?))),
+            "BACKUP" => arity(0).map(|_| Instr::Backup),
+            "RESTORE" => arity(0).map(|_| Instr::Restore),
+            "STRCMP" => arity(2).and_then(|_| Some(Instr::Strcmp(reg(0)?, reg(1)?))),
+            "STRCPY" => arity(2).and_then(|_| Some(Instr::Strcpy(reg(0)?, reg(1)?))),
+            "HALT" => arity(0).map(|_| Instr::Halt),
+            "BRKP" => arity(0).map(|_| Instr::Brkp),
+            "NOP" => arity(0).map(|_| Instr::Nop),
+            _ => None,
+        }
+    }
+}
+
+/// Splits on commas outside string literals.
+fn split_args(text: &str) -> Option<Vec<String>> {
+    let mut args = Vec::new();
+    let mut current = String::new();
+    let mut in_str = false;
+    for c in text.chars() {
+        match c {
+            '"' => {
+                in_str = !in_str;
+                current.push(c);
+            }
+            ',' if !in_str => args.push(std::mem::take(&mut current)),
+            _ => current.push(c),
+        }
+    }
+    if in_str {
+        return None;
+    }
+    if !current.trim().is_empty() || !args.is_empty() {
+        args.push(current);
+    }
+    Some(args.into_iter().map(|arg| arg.trim().to_owned()).collect())
+}
+
+fn parse_operand(text: &str) -> Option<Operand> {
+    if let Some(inner) = text.strip_prefix('[').and_then(|t| t.strip_suffix(']')) {
+        let inner = inner.trim();
+        if let Some(reg) = Reg::from_name(inner) {
+            return Some(Operand::MemReg(reg));
+        }
+        return inner.parse().ok().map(Operand::MemInt);
+    }
+    if let Some(s) = text.strip_prefix('"').and_then(|t| t.strip_suffix('"')) {
+        return Some(Operand::Str(s.to_owned()));
+    }
+    if let Some(reg) = Reg::from_name(text) {
+        return Some(Operand::Reg(reg));
+    }
+    text.parse().ok().map(Operand::Int)
+}
@@ -0,0 +1,879 @@
+//! An XSM machine emulated inside xdb, used in place of the xsm binary.
+//!
+//! Memory is 128 pages of 512 words and the disk is `disk.xfs` in the
+//! working directory, 512 blocks of 512 words with each word stored as
+//! 16 bytes padded with NULs, as written by xfs-interface.
+//! Page 0 holds the bootstrap, which loads disk block 0 into page 1
+//! and jumps to it, and the interrupt vector table at words 492 to 511:
+//! exception handler, timer, disk, console, then INT 4 to INT 19.
+//! ENCRYPT is not supported.
+
+mod instr;
+#[cfg(test)]
+mod tests;
+
+use std::collections::VecDeque;
+use std::fs::{self, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::backend::{BackendError, DebugBackend, End, Status, Stop};
+use crate::expr::Reg;
+use crate::parse;
+use crate::word::XSMWord;
+use crate::xsm::{Mode, XSMRegs};
+use instr::{ArithOp, CmpOp, Instr, Operand};
+
+pub const PAGE_LEN: usize = 512;
+pub const NUM_PAGES: usize = 128;
+const MEM_LEN: usize = PAGE_LEN * NUM_PAGES;
+const DISK_BLOCKS: usize = 512;
+/// Bytes per word in disk.xfs
+const DISK_WORD_LEN: usize = 16;
+const IVT_BASE: usize = 492;
+const IVT_TIMER: usize = 1;
+const IVT_DISK: usize = 2;
+const IVT_CONSOLE: usize = 3;
+/// Instructions executed per `poll` while running.
+const POLL_CHUNK: usize = 100_000;
+
+const EC_PAGE_FAULT: i64 = 0;
+const EC_ILLEGAL_INSTR: i64 = 1;
+const EC_ILLEGAL_MEM: i64 = 2;
+const EC_ARITH: i64 = 3;
+
+/// The command line options of xsm that apply to the emulator.
+/// Timings are in instructions; a timer of 0 disables it.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub disk_file: PathBuf,
+    pub timer: usize,
+    pub disk: usize,
+    pub console: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            disk_file: PathBuf::from("disk.xfs"),
+            timer: 20,
+            disk: 20,
+            console: 20,
+        }
+    }
+}
+
+impl Config {
+    /// Parses xsm options such as `--timer 100`. `--debug` is accepted
+    /// and ignored, since the emulator is always debugged.
+    pub fn from_args(args: &[String]) -> Result<Config, String> {
+        let mut config = Config::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let value = match arg.as_str() {
+                "--debug" => continue,
+                "--timer" => &mut config.timer,
+                "--disk" => &mut config.disk,
+                "--console" => &mut config.console,
+                _ => return Err(format!("Unknown xsm option '{}'", arg)),
+            };
+            *value = args
+                .next()
+                .and_then(|n| n.parse().ok())
+                .ok_or_else(|| format!("{} needs a number", arg))?;
+        }
+        Ok(config)
+    }
+}
+
+/// Why an instruction could not be completed.
+enum Fault {
+    /// Handled by the OS in user mode
+    Exception { ec: i64, epn: usize, ema: i64 },
+    /// Stops the machine, like any exception in kernel mode
+    Error(String),
+}
+
+/// What happens after an instruction.
+enum Flow {
+    Next,
+    Jump(i64),
+    Halt,
+    /// INI found no console input; the instruction is retried.
+    Wait,
+}
+
+struct DiskOp {
+    remaining: usize,
+    is_load: bool,
+    page: usize,
+    block: usize,
+}
+
+pub struct Machine {
+    config: Config,
+    /// Indexed by `reg_index`.
+    /// Words are strings, as xsm stores them; they become `XSMWord`s
+    /// when read by the debugger.
+    regs: Vec<String>,
+    memory: Vec<String>,
+    disk: Vec<String>,
+    is_user: bool,
+    halted: bool,
+    /// User mode instructions since the last timer interrupt
+    timer_count: usize,
+    disk_op: Option<DiskOp>,
+    /// Instructions left before a pending IN completes
+    console_op: Option<usize>,
+    pending_disk: bool,
+    pending_console: bool,
+    input: VecDeque<String>,
+    output: Vec<String>,
+    watched: Vec<usize>,
+    watch_hit: Option<usize>,
+    brkp_hit: bool,
+    waiting_input: bool,
+    /// The last instruction stepped was IN
+    console_read: bool,
+    /// IP and text of the instruction executed last
+    prev: Option<(usize, String)>,
+    running: bool,
+    /// Stop running at the next poll
+    interrupted: bool,
+    /// Reported with the next stop
+    disk_error: Option<BackendError>,
+}
+
+fn reg_index(reg: Reg) -> usize {
+    match reg {
+        Reg::R(i) => i,
+        Reg::P(i) => 20 + i,
+        Reg::BP => 24,
+        Reg::SP => 25,
+        Reg::IP => 26,
+        Reg::PTBR => 27,
+        Reg::PTLR => 28,
+        Reg::EIP => 29,
+        Reg::EC => 30,
+        Reg::EPN => 31,
+        Reg::EMA => 32,
+    }
+}
+
+const NUM_REGS: usize = 33;
+
+fn illegal_instr() -> Fault {
+    Fault::Exception {
+        ec: EC_ILLEGAL_INSTR,
+        epn: 0,
+        ema: 0,
+    }
+}
+
+/// Integer operands that are not integers are illegal.
+fn int(word: &str) -> Result<i64, Fault> {
+    word.trim().parse().map_err(|_| illegal_instr())
+}
+
+impl Machine {
+    pub fn new(config: Config) -> Result<Machine, BackendError> {
+        let disk = Self::read_disk(&config.disk_file).map_err(|source| BackendError::Disk {
+            path: config.disk_file.clone(),
+            source,
+        })?;
+        let mut memory = vec![String::new(); MEM_LEN];
+        memory[0] = "LOADI 1, 0".to_owned();
+        memory[2] = "JMP 512".to_owned();
+        let handlers = [1024, 2048, 3072, 4096];
+        for (i, word) in memory[IVT_BASE..PAGE_LEN].iter_mut().enumerate() {
+            let addr = handlers
+                .get(i)
+                .copied()
+                .unwrap_or_else(|| 5120 + 1024 * (i - 4));
+            *word = addr.to_string();
+        }
+        Ok(Machine {
+            config,
+            regs: vec!["0".to_owned(); NUM_REGS],
+            memory,
+            disk,
+            is_user: false,
+            halted: false,
+            timer_count: 0,
+            disk_op: None,
+            console_op: None,
+            pending_disk: false,
+            pending_console: false,
+            input: VecDeque::new(),
+            output: Vec::new(),
+            watched: Vec::new(),
+            watch_hit: None,
+            brkp_hit: false,
+            waiting_input: false,
+            console_read: false,
+            prev: None,
+            running: false,
+            interrupted: false,
+            disk_error: None,
+        })
+    }
+
+    fn read_disk(path: &Path) -> std::io::Result<Vec<String>> {
+        let bytes = fs::read(path)?;
+        let mut disk = vec![String::new(); DISK_BLOCKS * PAGE_LEN];
+        for (word, chunk) in disk.iter_mut().zip(bytes.chunks(DISK_WORD_LEN)) {
+            let end = chunk.iter().position(|&b| b == 0).unwrap_or(chunk.len());
+            *word = String::from_utf8_lossy(&chunk[..end]).into_owned();
+        }
+        Ok(disk)
+    }
+
+    /// Writes a block back to disk.xfs.
+    fn write_disk_block(&self, block: usize) -> std::io::Result<()> {
+        let mut bytes = vec![0; PAGE_LEN * DISK_WORD_LEN];
+        let words = &self.disk[block * PAGE_LEN..(block + 1) * PAGE_LEN];
+        for (chunk, word) in bytes.chunks_mut(DISK_WORD_LEN).zip(words) {
+            let len = word.len().min(DISK_WORD_LEN - 1);
+            chunk[..len].copy_from_slice(&word.as_bytes()[..len]);
+        }
+        let mut file = OpenOptions::new()
+            .write(true)
+            .open(&self.config.disk_file)?;
+        file.seek(SeekFrom::Start((block * bytes.len()) as u64))?;
+        file.write_all(&bytes)
+    }
+
+    fn reg(&self, reg: Reg) -> &str {
+        &self.regs[reg_index(reg)]
+    }
+
+    fn set(&mut self, reg: Reg, value: String) {
+        self.regs[reg_index(reg)] = value;
+    }
+
+    fn ip(&self) -> i64 {
+        self.reg(Reg::IP).parse().unwrap_or(-1)
+    }
+
+    /// User mode programs may only use R0 to R19, BP, SP and read IP.
+    fn get_reg(&self, reg: Reg) -> Result<String, Fault> {
+        match reg {
+            Reg::R(_) | Reg::BP | Reg::SP | Reg::IP => {}
+            _ if self.is_user => return Err(illegal_instr()),
+            _ => {}
+        }
+        Ok(self.reg(reg).to_owned())
+    }
+
+    fn set_reg(&mut self, reg: Reg, value: String) -> Result<(), Fault> {
+        match reg {
+            Reg::IP => return Err(illegal_instr()),
+            Reg::R(_) | Reg::BP | Reg::SP => {}
+            _ if self.is_user => return Err(illegal_instr()),
+            _ => {}
+        }
+        self.set(reg, value);
+        Ok(())
+    }
+
+    /// Translates an address of the current mode to a physical one.
+    /// In user mode, also returns the address of the page table entry used.
+    fn translate(&self, addr: i64, write: bool) -> Result<(usize, Option<usize>), Fault> {
+        if !self.is_user {
+            return if addr >= 0 && (addr as usize) < MEM_LEN {
+                Ok((addr as usize, None))
+            } else {
+                Err(Fault::Error(format!("Address {} is out of memory", addr)))
+            };
+        }
+        let illegal = Fault::Exception {
+            ec: EC_ILLEGAL_MEM,
+            epn: 0,
+            ema: addr,
+        };
+        let ptbr = int(self.reg(Reg::PTBR))?;
+        let ptlr = int(self.reg(Reg::PTLR))?;
+        if addr < 0 || addr / PAGE_LEN as i64 >= ptlr {
+            return Err(illegal);
+        }
+        let page = addr as usize / PAGE_LEN;
+        let entry = if ptbr < 0 {
+            None
+        } else {
+            (ptbr as usize).checked_add(page * 2)
+        };
+        let entry = match entry {
+            Some(entry) if entry < MEM_LEN - 1 => entry,
+            _ => return Err(Fault::Error(format!("PTBR {} is out of memory", ptbr))),
+        };
+        let aux = self.memory[entry + 1].as_bytes();
+        if aux.get(1) != Some(&b'1') {
+            return Err(Fault::Exception {
+                ec: EC_PAGE_FAULT,
+                epn: page,
+                ema: addr,
+            });
+        }
+        if write && aux.get(2) != Some(&b'1') {
+            return Err(illegal);
+        }
+        let phy_page = int(&self.memory[entry])?;
+        if phy_page < 0 || phy_page as usize >= NUM_PAGES {
+            return Err(illegal);
+        }
+        Ok((
+            phy_page as usize * PAGE_LEN + addr as usize % PAGE_LEN,
+            Some(entry),
+        ))
+    }
+
+    /// Sets the reference bit, and the dirty bit on writes,
+    /// of the page table entry at `entry`.
+    fn touch(&mut self, entry: Option<usize>, write: bool) {
+        if let Some(entry) = entry {
+            let mut aux: Vec<u8> = self.memory[entry + 1].bytes().collect();
+            aux.resize(4, b'0');
+            aux[0] = b'1';
+            if write {
+                aux[3] = b'1';
+            }
+            self.memory[entry + 1] = String::from_utf8_lossy(&aux).into_owned();
+        }
+    }
+
+    fn read_mem(&mut self, addr: i64) -> Result<String, Fault> {
+        let (phy, entry) = self.translate(addr, false)?;
+        self.touch(entry, false);
+        Ok(self.memory[phy].clone())
+    }
+
+    fn write_mem(&mut self, addr: i64, value: String) -> Result<(), Fault> {
+        let (phy, entry) = self.translate(addr, true)?;
+        self.touch(entry, true);
+        self.write_phy(phy, value);
+        Ok(())
+    }
+
+    fn write_phy(&mut self, phy: usize, value: String) {
+        if self.watched.contains(&phy) {
+            self.watch_hit = Some(phy);
+        }
+        self.memory[phy] = value;
+    }
+
+    fn value(&mut self, op: &Operand) -> Result<String, Fault> {
+        match op {
+            Operand::Reg(reg) => self.get_reg(*reg),
+            Operand::Int(n) => Ok(n.to_string()),
+            Operand::Str(s) => Ok(s.clone()),
+            Operand::MemReg(reg) => {
+                let addr = int(&self.get_reg(*reg)?)?;
+                self.read_mem(addr)
+            }
+            Operand::MemInt(addr) => self.read_mem(*addr as i64),
+        }
+    }
+
+    fn push(&mut self, value: String) -> Result<(), Fault> {
+        let sp = int(self.reg(Reg::SP))? + 1;
+        self.write_mem(sp, value)?;
+        self.set(Reg::SP, sp.to_string());
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<String, Fault> {
+        let sp = int(self.reg(Reg::SP))?;
+        let value = self.read_mem(sp)?;
+        self.set(Reg::SP, (sp - 1).to_string());
+        Ok(value)
+    }
+
+    /// A page number or block number operand.
+    fn index(&mut self, op: &Operand, count: usize) -> Result<usize, Fault> {
+        let i = int(&self.value(op)?)?;
+        if i < 0 || i as usize >= count {
+            return Err(Fault::Error(format!("{} is not a page or block", i)));
+        }
+        Ok(i as usize)
+    }
+
+    fn privileged(&self) -> Result<(), Fault> {
+        if self.is_user {
+            Err(illegal_instr())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// The text of the instruction at the current IP,
+    /// without touching the page table.
+    fn peek_instr(&self) -> String {
+        match self.translate(self.ip(), false) {
+            Ok((phy, _)) if phy + 1 < MEM_LEN => {
+                format!("{}{}", self.memory[phy], self.memory[phy + 1])
+            }
+            _ => String::new(),
+        }
+    }
+
+    fn fetch(&mut self) -> Result<Instr, Fault> {
+        let ip = self.ip();
+        let text = format!("{}{}", self.read_mem(ip)?, self.read_mem(ip + 1)?);
+        let instr = Instr::parse(&text).ok_or_else(illegal_instr);
+        self.prev = Some((ip as usize, text.trim().to_owned()));
+        instr
+    }
+
+    fn execute(&mut self) -> Result<Flow, Fault> {
+        let instr = self.fetch()?;
+        let ip = self.ip();
+        match instr {
+            Instr::Mov(dst, src) => {
+                let value = self.value(&src)?;
+                match dst {
+                    Operand::Reg(reg) => self.set_reg(reg, value)?,
+                    Operand::MemReg(reg) => {
+                        let addr = int(&self.get_reg(reg)?)?;
+                        self.write_mem(addr, value)?;
+                    }
+                    Operand::MemInt(addr) => self.write_mem(addr as i64, value)?,
+                    _ => return Err(illegal_instr()),
+                }
+            }
+            Instr::Arith(op, reg, src) => {
+                let a = int(&self.get_reg(reg)?)?;
+                let b = int(&self.value(&src)?)?;
+                let result = match op {
+                    ArithOp::Add => a.wrapping_add(b),
+                    ArithOp::Sub => a.wrapping_sub(b),
+                    ArithOp::Mul => a.wrapping_mul(b),
+                    ArithOp::Div | ArithOp::Mod if b == 0 => {
+                        return Err(Fault::Exception {
+                            ec: EC_ARITH,
+                            epn: 0,
+                            ema: 0,
+                        })
+                    }
+                    ArithOp::Div => a.wrapping_div(b),
+                    ArithOp::Mod => a.wrapping_rem(b),
+                };
+                self.set_reg(reg, result.to_string())?;
+            }
+            Instr::Inr(reg) => {
+                let a = int(&self.get_reg(reg)?)?;
+                self.set_reg(reg, (a.wrapping_add(1)).to_string())?;
+            }
+            Instr::Dcr(reg) => {
+                let a = int(&self.get_reg(reg)?)?;
+                self.set_reg(reg, (a.wrapping_sub(1)).to_string())?;
+            }
+            Instr::Cmp(op, reg, src) => {
+                let a = self.get_reg(reg)?;
+                let b = self.value(&src)?;
+                // Integers compare as numbers, anything else as strings.
+                let ord = match (a.parse::<i64>(), b.parse::<i64>()) {
+                    (Ok(a), Ok(b)) => a.cmp(&b),
+                    _ => a.cmp(&b),
+                };
+                let result = match op {
+                    CmpOp::Lt => ord.is_lt(),
+                    CmpOp::Gt => ord.is_gt(),
+                    CmpOp::Eq => ord.is_eq(),
+                    CmpOp::Ne => ord.is_ne(),
+                    CmpOp::Ge => ord.is_ge(),
+                    CmpOp::Le => ord.is_le(),
+                };
+                self.set_reg(reg, (result as i64).to_string())?;
+            }
+            Instr::Jz(reg, target) => {
+                if self.get_reg(reg)?.parse::<i64>() == Ok(0) {
+                    return Ok(Flow::Jump(int(&self.value(&target)?)?));
+                }
+            }
+            Instr::Jnz(reg, target) => {
+                if self.get_reg(reg)?.parse::<i64>() != Ok(0) {
+                    return Ok(Flow::Jump(int(&self.value(&target)?)?));
+                }
+            }
+            Instr::Jmp(target) => return Ok(Flow::Jump(int(&self.value(&target)?)?)),
+            Instr::Push(reg) => {
+                let value = self.get_reg(reg)?;
+                self.push(value)?;
+            }
+            Instr::Pop(reg) => {
+                let value = self.pop()?;
+                self.set_reg(reg, value)?;
+            }
+            Instr::Call(target) => {
+                let target = int(&self.value(&target)?)?;
+                self.push((ip + 2).to_string())?;
+                return Ok(Flow::Jump(target));
+            }
+            Instr::Ret => return Ok(Flow::Jump(int(&self.pop()?)?)),
+            Instr::Int(n) => {
+                if !(4..=19).contains(&n) {
+                    return Err(illegal_instr());
+                }
+                self.push((ip + 2).to_string())?;
+                self.is_user = false;
+                return Ok(Flow::Jump(int(&self.memory[IVT_BASE + n])?));
+            }
+            Instr::Iret => {
+                self.privileged()?;
+                self.is_user = true;
+                let target = self.pop();
+                if target.is_err() {
+                    self.is_user = false;
+                }
+                return Ok(Flow::Jump(int(&target?)?));
+            }
+            Instr::In => {
+                self.privileged()?;
+                self.console_op = Some(self.config.console);
+            }
+            Instr::Ini => {
+                self.privileged()?;
+                match self.input.pop_front() {
+                    Some(line) => self.set(Reg::P(0), line),
+                    None => return Ok(Flow::Wait),
+                }
+            }
+            Instr::Out => {
+                self.privileged()?;
+                self.output.push(self.reg(Reg::P(0)).to_owned());
+            }
+            Instr::Load(page, block) => self.start_disk_op(true, &page, &block)?,
+            Instr::Store(page, block) => self.start_disk_op(false, &page, &block)?,
+            Instr::Loadi(page, block) => {
+                self.privileged()?;
+                let page = self.index(&page, NUM_PAGES)?;
+                let block = self.index(&block, DISK_BLOCKS)?;
+                self.transfer(true, page, block);
+            }
+            Instr::Backup => {
+                self.privileged()?;
+                self.push(self.reg(Reg::BP).to_owned())?;
+                for i in 0..20 {
+                    self.push(self.reg(Reg::R(i)).to_owned())?;
+                }
+            }
+            Instr::Restore => {
+                self.privileged()?;
+                for i in (0..20).rev() {
+                    let value = self.pop()?;
+                    self.set(Reg::R(i), value);
+                }
+                let bp = self.pop()?;
+                self.set(Reg::BP, bp);
+            }
+            Instr::Strcmp(a, b) => {
+                let ord = self.get_reg(a)?.cmp(&self.get_reg(b)?);
+                self.set_reg(a, (ord as i64).to_string())?;
+            }
+            Instr::Strcpy(a, b) => {
+                let value = self.get_reg(b)?;
+                self.set_reg(a, value)?;
+            }
+            Instr::Halt => {
+                self.privileged()?;
+                return Ok(Flow::Halt);
+            }
+            Instr::Brkp => self.brkp_hit = true,
+            Instr::Nop => {}
+        }
+        Ok(Flow::Next)
+    }
+
+    /// LOAD and STORE complete after the disk delay, with a disk interrupt.
+    fn start_disk_op(
+        &mut self,
+        is_load: bool,
+        page: &Operand,
+        block: &Operand,
+    ) -> Result<(), Fault> {
+        self.privileged()?;
+        self.disk_op = Some(DiskOp {
+            remaining: self.config.disk,
+            is_load,
+            page: self.index(page, NUM_PAGES)?,
+            block: self.index(block, DISK_BLOCKS)?,
+        });
+        Ok(())
+    }
+
+    /// Copies a disk block into a memory page, or a page to a block.
+    fn transfer(&mut self, is_load: bool, page: usize, block: usize) {
+        let (mem, disk) = (page * PAGE_LEN, block * PAGE_LEN);
+        if is_load {
+            for i in 0..PAGE_LEN {
+                self.write_phy(mem + i, self.disk[disk + i].clone());
+            }
+        } else {
+            self.disk[disk..disk + PAGE_LEN].clone_from_slice(&self.memory[mem..mem + PAGE_LEN]);
+            if let Err(source) = self.write_disk_block(block) {
+                self.disk_error = Some(BackendError::Disk {
+                    path: self.config.disk_file.clone(),
+                    source,
+                });
+            }
+        }
+    }
+
+    /// Executes one instruction, then advances the devices
+    /// and takes a pending interrupt if in user mode.
+    fn step_one(&mut self) {
+        let ip = self.ip();
+        let was_user = self.is_user;
+        match self.execute() {
+            Ok(Flow::Next) => self.set(Reg::IP, (ip + 2).to_string()),
+            Ok(Flow::Jump(target)) => self.set(Reg::IP, target.to_string()),
+            Ok(Flow::Halt) => {
+                self.halted = true;
+                return;
+            }
+            Ok(Flow::Wait) => {
+                self.waiting_input = true;
+                return;
+            }
+            Err(fault) => self.fault(fault, ip),
+        }
+        if self.halted {
+            return;
+        }
+        self.tick(was_user);
+        if self.is_user {
+            self.enter_interrupt();
+        }
+    }
+
+    fn fault(&mut self, fault: Fault, ip: i64) {
+        match fault {
+            Fault::Exception { ec, epn, ema } if self.is_user => {
+                self.set(Reg::EIP, ip.to_string());
+                self.set(Reg::EC, ec.to_string());
+                self.set(Reg::EPN, epn.to_string());
+                self.set(Reg::EMA, ema.to_string());
+                self.is_user = false;
+                let handler = self.memory[IVT_BASE].clone();
+                self.set(Reg::IP, handler);
+            }
+            Fault::Exception { ec, .. } => {
+                self.output.push(format!(
+                    "Error: exception {} in kernel mode at IP = {}",
+                    ec, ip
+                ));
+                self.halted = true;
+            }
+            Fault::Error(msg) => {
+                self.output.push(format!("Error: {} at IP = {}", msg, ip));
+                self.halted = true;
+            }
+        }
+    }
+
+    fn tick(&mut self, was_user: bool) {
+        if let Some(op) = &mut self.disk_op {
+            op.remaining = op.remaining.saturating_sub(1);
+            if op.remaining == 0 {
+                let op = self.disk_op.take().unwrap();
+                self.transfer(op.is_load, op.page, op.block);
+                self.pending_disk = true;
+            }
+        }
+        if let Some(remaining) = &mut self.console_op {
+            *remaining = remaining.saturating_sub(1);
+            if *remaining == 0 {
+                if let Some(line) = self.input.pop_front() {
+                    self.console_op = None;
+                    self.set(Reg::P(0), line);
+                    self.pending_console = true;
+                }
+            }
+        }
+        if was_user {
+            self.timer_count += 1;
+        }
+    }
+
+    /// Enters the handler of a pending interrupt, saving IP on the user stack.
+    fn enter_interrupt(&mut self) {
+        let ivt = if self.pending_disk {
+            self.pending_disk = false;
+            IVT_DISK
+        } else if self.pending_console {
+            self.pending_console = false;
+            IVT_CONSOLE
+        } else if self.config.timer > 0 && self.timer_count >= self.config.timer {
+            self.timer_count = 0;
+            IVT_TIMER
+        } else {
+            return;
+        };
+        let ip = self.ip();
+        if let Err(fault) = self.push(ip.to_string()) {
+            self.fault(fault, ip);
+            return;
+        }
+        self.is_user = false;
+        let handler = self.memory[IVT_BASE + ivt].clone();
+        self.set(Reg::IP, handler);
+    }
+
+    fn stop(&mut self, executed: usize) -> Stop {
+        let mode = if self.is_user { "USER" } else { "KERNEL" };
+        let next_instr = self.peek_instr().trim().to_owned();
+        let ip = self.ip().max(0) as usize;
+        let mut text = match &self.prev {
+            Some((ip, instr)) => format!("Previous instruction at IP = {}: {}\n", ip, instr),
+            None => "Previous instruction: none\n".to_owned(),
+        };
+        text.push_str(&format!("Mode: {}\n", mode));
+        text.push_str(&format!(
+            "Next instruction at IP = {}: {}\n",
+            ip, next_instr
+        ));
+        if self.waiting_input {
+            self.output
+                .push("INI is waiting for console input".to_owned());
+        }
+        Stop {
+            output: std::mem::take(&mut self.output),
+            watch_addr: self.watch_hit.take(),
+            end: if self.halted { Some(End::Halted) } else { None },
+            status: if self.halted {
+                None
+            } else {
+                Some(Status {
+                    text,
+                    mode: if self.is_user {
+                        Mode::User
+                    } else {
+                        Mode::Kernel
+                    },
+                    ip,
+                    next_instr,
+                })
+            },
+            executed,
+            executed_unknown: false,
+            console_read: std::mem::take(&mut self.console_read),
+            input: Vec::new(),
+            error: self.disk_error.take(),
+        }
+    }
+
+    /// Executes up to n instructions, stopping early on HALT,
+    /// a watchpoint or INI without input. Steps also stop after an IN,
+    /// as xsm is stepped.
+    fn run_for(&mut self, n: usize, stop_at_brkp: bool) -> usize {
+        self.waiting_input = false;
+        let mut executed = 0;
+        while executed < n && !self.halted {
+            self.brkp_hit = false;
+            let is_in = !stop_at_brkp && parse::opcode(&self.peek_instr()) == "IN";
+            self.step_one();
+            if self.waiting_input {
+                break;
+            }
+            executed += 1;
+            if self.watch_hit.is_some() || (stop_at_brkp && self.brkp_hit) {
+                break;
+            }
+            if is_in {
+                self.console_read = true;
+                break;
+            }
+        }
+        executed
+    }
+}
+
+impl DebugBackend for Machine {
+    fn initial_stop(&mut self) -> Stop {
+        self.stop(0)
+    }
+
+    fn step(&mut self, n: usize) -> Result<Stop, BackendError> {
+        let executed = self.run_for(n, false);
+        Ok(self.stop(executed))
+    }
+
+    fn run(&mut self) -> Result<(), BackendError> {
+        self.running = true;
+        Ok(())
+    }
+
+    /// Runs a bounded number of instructions per call
+    /// so that the window stays responsive.
+    fn poll(&mut self) -> Result<Option<Stop>, BackendError> {
+        if !self.running {
+            return Ok(None);
+        }
+        if self.interrupted {
+            self.interrupted = false;
+            self.running = false;
+            return Ok(Some(self.stop(0)));
+        }
+        let executed = self.run_for(POLL_CHUNK, true);
+        let stopped =
+            self.halted || self.watch_hit.is_some() || self.brkp_hit || executed < POLL_CHUNK;
+        if !stopped {
+            return Ok(None);
+        }
+        self.running = false;
+        Ok(Some(self.stop(executed)))
+    }
+
+    fn interrupt(&mut self) -> Result<(), BackendError> {
+        self.interrupted = self.running;
+        Ok(())
+    }
+
+    fn read_regs(&mut self) -> Result<XSMRegs, BackendError> {
+        let mut regs = XSMRegs::default();
+        for (i, r) in regs.r.iter_mut().enumerate() {
+            *r = XSMWord::parse(self.reg(Reg::R(i)));
+        }
+        for (i, p) in regs.p.iter_mut().enumerate() {
+            *p = XSMWord::parse(self.reg(Reg::P(i)));
+        }
+        regs.bp = XSMWord::parse(self.reg(Reg::BP));
+        regs.sp = XSMWord::parse(self.reg(Reg::SP));
+        regs.ip = XSMWord::parse(self.reg(Reg::IP));
+        regs.ptbr = XSMWord::parse(self.reg(Reg::PTBR));
+        regs.ptlr = XSMWord::parse(self.reg(Reg::PTLR));
+        regs.eip = XSMWord::parse(self.reg(Reg::EIP));
+        regs.ec = XSMWord::parse(self.reg(Reg::EC));
+        regs.epn = XSMWord::parse(self.reg(Reg::EPN));
+        regs.ema = XSMWord::parse(self.reg(Reg::EMA));
+        Ok(regs)
+    }
+
+    fn read_mem_page(&mut self, page: usize) -> Result<Vec<XSMWord>, BackendError> {
+        match self.memory.get(page * PAGE_LEN..(page + 1) * PAGE_LEN) {
+            Some(words) => Ok(words.iter().map(|word| XSMWord::parse(word)).collect()),
+            None => Err(BackendError::PageOutOfRange(page)),
+        }
+    }
+
+    fn watch(&mut self, addr: usize) -> Result<(), BackendError> {
+        if !self.watched.contains(&addr) {
+            self.watched.push(addr);
+        }
+        Ok(())
+    }
+
+    fn watch_clear(&mut self) -> Result<(), BackendError> {
+        self.watched.clear();
+        Ok(())
+    }
+
+    fn write_input(&mut self, line: &str) -> Result<(), BackendError> {
+        self.input.push_back(line.to_owned());
+        Ok(())
+    }
+
+    fn restart(&self) -> Result<Box<dyn DebugBackend>, BackendError> {
+        Ok(Box::new(Machine::new(self.config.clone())?))
+    }
+}
@@ -0,0 +1,292 @@
+//! Small programs run on the emulator, written straight into memory.
+
+use super::*;
+
+/// Physical page of the page table set up by `enter_user_mode`.
+const PT_PAGE: usize = 3;
+/// Physical pages of logical pages 0 (code) and 1 (stack).
+const CODE_PAGE: usize = 10;
+const STACK_PAGE: usize = 11;
+
+/// A machine with an empty disk.xfs in a folder of its own.
+/// Devices are those of `config`; the timer is off unless set.
+fn machine(name: &str, config: Config) -> Machine {
+    let dir = std::env::temp_dir().join(format!("xdb-emulator-{}-{}", std::process::id(), name));
+    fs::create_dir_all(&dir).unwrap();
+    let disk_file = dir.join("disk.xfs");
+    fs::write(&disk_file, b"").unwrap();
+    Machine::new(Config {
+        disk_file,
+        ..config
+    })
+    .unwrap()
+}
+
+fn config() -> Config {
+    Config {
+        timer: 0,
+        ..Config::default()
+    }
+}
+
+/// Writes one instruction per two words from physical address `addr` on.
+fn load(m: &mut Machine, addr: usize, program: &[&str]) {
+    for (i, instr) in program.iter().enumerate() {
+        m.memory[addr + 2 * i] = (*instr).to_owned();
+        m.memory[addr + 2 * i + 1] = String::new();
+    }
+}
+
+fn set(m: &mut Machine, reg: Reg, value: &str) {
+    m.set(reg, value.to_owned());
+}
+
+/// Sets up a page table with logical page 0 as code, page 1 as stack,
+/// both writable, and page 2 not in memory, then starts the user program
+/// at logical address 0 with an empty stack.
+fn enter_user_mode(m: &mut Machine, program: &[&str]) {
+    let pt = PT_PAGE * PAGE_LEN;
+    for (i, (page, aux)) in [(CODE_PAGE, "0110"), (STACK_PAGE, "0110"), (12, "0000")]
+        .iter()
+        .enumerate()
+    {
+        m.memory[pt + 2 * i] = page.to_string();
+        m.memory[pt + 2 * i + 1] = (*aux).to_owned();
+    }
+    set(m, Reg::PTBR, &pt.to_string());
+    set(m, Reg::PTLR, "3");
+    set(m, Reg::SP, "511");
+    set(m, Reg::IP, "0");
+    load(m, CODE_PAGE * PAGE_LEN, program);
+    m.is_user = true;
+}
+
+/// Runs kernel code from physical address 512 on.
+fn enter_kernel_code(m: &mut Machine, program: &[&str]) {
+    load(m, 512, program);
+    set(m, Reg::IP, "512");
+}
+
+fn run(m: &mut Machine, n: usize) -> usize {
+    m.run_for(n, false)
+}
+
+/// The exception registers as EC, EPN, EMA and EIP.
+fn exception(m: &Machine) -> [&str; 4] {
+    [
+        m.reg(Reg::EC),
+        m.reg(Reg::EPN),
+        m.reg(Reg::EMA),
+        m.reg(Reg::EIP),
+    ]
+}
+
+#[test]
+fn boot_loads_block_0_and_jumps_to_it() {
+    let mut m = machine("boot", config());
+    m.disk[0] = "NOP".to_owned();
+    assert_eq!(run(&mut m, 2), 2);
+    assert_eq!(m.ip(), 512);
+    assert_eq!(m.peek_instr(), "NOP");
+}
+
+#[test]
+fn user_addresses_go_through_the_page_table() {
+    let mut m = machine("paging", config());
+    enter_user_mode(&mut m, &["MOV R0, 7", "MOV [600], R0", "MOV R1, [600]"]);
+    assert_eq!(run(&mut m, 3), 3);
+    assert_eq!(m.memory[STACK_PAGE * PAGE_LEN + 88], "7");
+    assert_eq!(m.reg(Reg::R(1)), "7");
+    // Reference bits are set on every access, dirty bits on writes.
+    let pt = PT_PAGE * PAGE_LEN;
+    assert_eq!(m.memory[pt + 1], "1110");
+    assert_eq!(m.memory[pt + 3], "1111");
+    assert!(m.is_user);
+}
+
+#[test]
+fn page_fault_enters_the_exception_handler() {
+    let mut m = machine("page-fault", config());
+    enter_user_mode(&mut m, &["NOP", "MOV R0, [1030]"]);
+    run(&mut m, 2);
+    assert!(!m.is_user);
+    assert_eq!(m.ip(), 1024);
+    assert_eq!(exception(&m), ["0", "2", "1030", "2"]);
+}
+
+#[test]
+fn illegal_accesses_and_instructions_are_exceptions() {
+    let cases: &[(&str, &str, [&str; 4])] = &[
+        ("beyond-ptlr", "MOV R0, [2000]", ["2", "0", "2000", "0"]),
+        ("privileged", "HALT", ["1", "0", "0", "0"]),
+        ("kernel-reg", "MOV R0, PTBR", ["1", "0", "0", "0"]),
+        ("div-zero", "DIV R0, R1", ["3", "0", "0", "0"]),
+    ];
+    for (name, instr, regs) in cases {
+        let mut m = machine(name, config());
+        enter_user_mode(&mut m, &[instr]);
+        run(&mut m, 1);
+        assert_eq!(m.ip(), 1024, "{}", instr);
+        assert_eq!(exception(&m), *regs, "{}", instr);
+    }
+}
+
+#[test]
+fn writing_a_read_only_page_is_an_exception() {
+    let mut m = machine("read-only", config());
+    enter_user_mode(&mut m, &["MOV [0], R0"]);
+    m.memory[PT_PAGE * PAGE_LEN + 1] = "0100".to_owned();
+    run(&mut m, 1);
+    assert_eq!(exception(&m), ["2", "0", "0", "0"]);
+}
+
+#[test]
+fn page_table_out_of_memory_stops_the_machine() {
+    for ptbr in &["-2", "65535", "9223372036854775807"] {
+        let mut m = machine("bad-ptbr", config());
+        enter_user_mode(&mut m, &["NOP"]);
+        set(&mut m, Reg::PTBR, ptbr);
+        run(&mut m, 1);
+        assert!(m.halted, "PTBR {}", ptbr);
+        assert_eq!(
+            m.output,
+            [format!("Error: PTBR {} is out of memory at IP = 0", ptbr)]
+        );
+    }
+}
+
+#[test]
+fn exception_in_kernel_mode_stops_the_machine() {
+    let mut m = machine("kernel-exception", config());
+    enter_kernel_code(&mut m, &["DIV R0, R1"]);
+    run(&mut m, 1);
+    assert!(m.halted);
+    assert_eq!(m.output, ["Error: exception 3 in kernel mode at IP = 512"]);
+}
+
+#[test]
+fn timer_interrupts_user_mode() {
+    let mut m = machine(
+        "timer",
+        Config {
+            timer: 2,
+            ..config()
+        },
+    );
+    enter_user_mode(&mut m, &["NOP", "NOP", "NOP"]);
+    run(&mut m, 1);
+    assert!(m.is_user);
+    run(&mut m, 1);
+    assert!(!m.is_user);
+    assert_eq!(m.ip(), 2048);
+    // IP is saved on the user stack.
+    assert_eq!(m.reg(Reg::SP), "512");
+    assert_eq!(m.memory[STACK_PAGE * PAGE_LEN], "4");
+}
+
+#[test]
+fn store_and_load_complete_after_the_disk_delay() {
+    let mut m = machine(
+        "disk",
+        Config {
+            disk: 2,
+            ..config()
+        },
+    );
+    m.memory[20 * PAGE_LEN] = "hello".to_owned();
+    enter_kernel_code(&mut m, &["STORE 20, 3", "NOP", "LOAD 21, 3", "NOP"]);
+    run(&mut m, 1);
+    assert_eq!(m.disk[3 * PAGE_LEN], "");
+    run(&mut m, 1);
+    assert_eq!(m.disk[3 * PAGE_LEN], "hello");
+    assert!(m.pending_disk);
+    let disk = Machine::read_disk(&m.config.disk_file).unwrap();
+    assert_eq!(disk[3 * PAGE_LEN], "hello");
+    run(&mut m, 1);
+    assert_eq!(m.memory[21 * PAGE_LEN], "");
+    run(&mut m, 1);
+    assert_eq!(m.memory[21 * PAGE_LEN], "hello");
+}
+
+#[test]
+fn out_prints_p0_and_in_raises_the_console_interrupt() {
+    let mut m = machine(
+        "console",
+        Config {
+            console: 2,
+            ..config()
+        },
+    );
+    enter_user_mode(&mut m, &["NOP"]);
+    m.is_user = false;
+    // IRET returns to logical address 0, saved on the user stack.
+    m.memory[STACK_PAGE * PAGE_LEN] = "0".to_owned();
+    set(&mut m, Reg::SP, "512");
+    enter_kernel_code(&mut m, &["MOV P0, \"hi\"", "OUT", "IN", "IRET"]);
+    m.write_input("typed").unwrap();
+    // A step ends right after IN.
+    assert_eq!(run(&mut m, 10), 3);
+    let stop = m.stop(3);
+    assert_eq!(stop.output, ["hi"]);
+    assert!(stop.console_read);
+    run(&mut m, 1);
+    assert_eq!(m.reg(Reg::P(0)), "typed");
+    assert!(!m.is_user);
+    assert_eq!(m.ip(), 4096);
+}
+
+#[test]
+fn ini_waits_for_input() {
+    let mut m = machine("ini", config());
+    enter_kernel_code(&mut m, &["INI", "NOP"]);
+    assert_eq!(run(&mut m, 10), 0);
+    assert!(m.waiting_input);
+    assert_eq!(m.ip(), 512);
+    m.write_input("line").unwrap();
+    assert_eq!(run(&mut m, 1), 1);
+    assert_eq!(m.reg(Reg::P(0)), "line");
+}
+
+#[test]
+fn backup_and_restore_save_registers_on_the_stack() {
+    let mut m = machine("backup", config());
+    for i in 0..20 {
+        set(&mut m, Reg::R(i), &i.to_string());
+    }
+    set(&mut m, Reg::BP, "7");
+    set(&mut m, Reg::SP, "5000");
+    enter_kernel_code(&mut m, &["BACKUP", "MOV R0, 99", "MOV BP, 1", "RESTORE"]);
+    run(&mut m, 1);
+    assert_eq!(m.reg(Reg::SP), "5021");
+    assert_eq!(m.memory[5001], "7");
+    assert_eq!(m.memory[5002], "0");
+    assert_eq!(m.memory[5021], "19");
+    run(&mut m, 3);
+    assert_eq!(m.reg(Reg::R(0)), "0");
+    assert_eq!(m.reg(Reg::R(19)), "19");
+    assert_eq!(m.reg(Reg::BP), "7");
+    assert_eq!(m.reg(Reg::SP), "5000");
+}
+
+#[test]
+fn int_enters_the_kernel_and_iret_returns() {
+    let mut m = machine("int", config());
+    enter_user_mode(&mut m, &["INT 7", "NOP"]);
+    load(&mut m, 8192, &["IRET"]);
+    run(&mut m, 1);
+    assert!(!m.is_user);
+    assert_eq!(m.ip(), 8192);
+    assert_eq!(m.memory[STACK_PAGE * PAGE_LEN], "2");
+    run(&mut m, 1);
+    assert!(m.is_user);
+    assert_eq!(m.ip(), 2);
+    assert_eq!(m.reg(Reg::SP), "511");
+}
+
+#[test]
+fn int_outside_4_to_19_is_illegal() {
+    let mut m = machine("bad-int", config());
+    enter_user_mode(&mut m, &["INT 3"]);
+    run(&mut m, 1);
+    assert_eq!(exception(&m), ["1", "0", "0", "0"]);
+}
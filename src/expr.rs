@@ -0,0 +1,563 @@
+//! Expression language for breakpoint conditions.
+//!
+//! Examples:
+//!     R1 == 5 && [PTBR + 4] == -1
+//!     mode == user && PTBR == 28672 + 20
+//!     v[SP] != 0
+//!
+//! `[addr]` reads a physical memory word and `v[addr]` a virtual one,
+//! translated through the current page table.
+//! Values are machine words, so registers and memory compare
+//! the same way they are shown.
+
+use std::fmt;
+
+use crate::word::XSMWord;
+use crate::xsm::XSMRegs;
+
+/// Machine state an expression is evaluated against.
+pub trait Context {
+    fn regs(&self) -> &XSMRegs;
+    fn is_user_mode(&self) -> bool;
+    /// Returns None if the address could not be read.
+    fn read_word(&mut self, addr: usize, is_virtual: bool) -> Option<XSMWord>;
+}
+
+#[derive(Debug, Clone)]
+pub enum ExprError {
+    Parse { pos: usize, msg: String },
+    Eval(String),
+}
+
+impl fmt::Display for ExprError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExprError::Parse { pos, msg } => write!(f, "Parse error at {}: {}", pos, msg),
+            ExprError::Eval(msg) => write!(f, "Evaluation error: {}", msg),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Reg {
+    R(usize),
+    P(usize),
+    BP,
+    SP,
+    IP,
+    PTBR,
+    PTLR,
+    EIP,
+    EC,
+    EPN,
+    EMA,
+}
+
+impl Reg {
+    pub fn from_name(name: &str) -> Option<Self> {
+        let name = name.to_ascii_uppercase();
+        let index = |prefix: &str, count: usize| {
+            name.strip_prefix(prefix)
+                .and_then(|i| i.parse::<usize>().ok())
+                .filter(|&i| i < count)
+        };
+        if let Some(i) = index("R", 20) {
+            return Some(Reg::R(i));
+        }
+        if let Some(i) = index("P", 4) {
+            return Some(Reg::P(i));
+        }
+        Some(match name.as_str() {
+            "BP" => Reg::BP,
+            "SP" => Reg::SP,
+            "IP" => Reg::IP,
+            "PTBR" => Reg::PTBR,
+            "PTLR" => Reg::PTLR,
+            "EIP" => Reg::EIP,
+            "EC" => Reg::EC,
+            "EPN" => Reg::EPN,
+            "EMA" => Reg::EMA,
+            _ => return None,
+        })
+    }
+
+    pub fn get<'a>(&self, regs: &'a XSMRegs) -> &'a XSMWord {
+        match *self {
+            Reg::R(i) => &regs.r[i],
+            Reg::P(i) => &regs.p[i],
+            Reg::BP => &regs.bp,
+            Reg::SP => &regs.sp,
+            Reg::IP => &regs.ip,
+            Reg::PTBR => &regs.ptbr,
+            Reg::PTLR => &regs.ptlr,
+            Reg::EIP => &regs.eip,
+            Reg::EC => &regs.ec,
+            Reg::EPN => &regs.epn,
+            Reg::EMA => &regs.ema,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BinOp {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum UnOp {
+    Neg,
+    Not,
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    Const(XSMWord),
+    Reg(Reg),
+    Mode,
+    Mem { addr: Box<Node>, is_virtual: bool },
+    Unary(UnOp, Box<Node>),
+    Binary(BinOp, Box<Node>, Box<Node>),
+}
+
+/// A parsed expression along with the text it was parsed from.
+#[derive(Debug, Clone)]
+pub struct Expr {
+    source: String,
+    root: Node,
+}
+
+impl Expr {
+    pub fn parse(source: &str) -> Result<Self, ExprError> {
+        let tokens = tokenize(source)?;
+        let mut parser = Parser { tokens, pos: 0 };
+        let root = parser.parse_or()?;
+        if let Some((pos, tok)) = parser.tokens.get(parser.pos) {
+            return Err(ExprError::Parse {
+                pos: *pos,
+                msg: format!("Unexpected {:?}", tok),
+            });
+        }
+        Ok(Self {
+            source: source.to_owned(),
+            root,
+        })
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn eval(&self, ctx: &mut dyn Context) -> Result<XSMWord, ExprError> {
+        eval(&self.root, ctx)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Int(i64),
+    Str(String),
+    Ident(String),
+    Op(&'static str),
+}
+
+const OPERATORS: [&str; 18] = [
+    "||", "&&", "==", "!=", "<=", ">=", "<", ">", "+", "-", "*", "/", "%", "!", "(", ")", "[", "]",
+];
+
+fn tokenize(source: &str) -> Result<Vec<(usize, Token)>, ExprError> {
+    let mut tokens = Vec::new();
+    let bytes = source.as_bytes();
+    let mut pos = 0;
+    while pos < bytes.len() {
+        let c = bytes[pos] as char;
+        if c.is_whitespace() {
+            pos += 1;
+        } else if c.is_ascii_digit() {
+            let start = pos;
+            while pos < bytes.len() && (bytes[pos] as char).is_ascii_digit() {
+                pos += 1;
+            }
+            let int = source[start..pos].parse().map_err(|_| ExprError::Parse {
+                pos: start,
+                msg: "Integer too large".to_owned(),
+            })?;
+            tokens.push((start, Token::Int(int)));
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let start = pos;
+            while pos < bytes.len()
+                && ((bytes[pos] as char).is_ascii_alphanumeric() || bytes[pos] == b'_')
+            {
+                pos += 1;
+            }
+            tokens.push((start, Token::Ident(source[start..pos].to_owned())));
+        } else if c == '"' {
+            let start = pos;
+            let end = source[pos + 1..].find('"').ok_or(ExprError::Parse {
+                pos: start,
+                msg: "Unterminated string".to_owned(),
+            })?;
+            tokens.push((start, Token::Str(source[pos + 1..pos + 1 + end].to_owned())));
+            pos += end + 2;
+        } else if let Some(op) = OPERATORS.iter().find(|op| source[pos..].starts_with(*op)) {
+            tokens.push((pos, Token::Op(op)));
+            pos += op.len();
+        } else {
+            return Err(ExprError::Parse {
+                pos,
+                msg: format!("Unexpected character '{}'", c),
+            });
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(_, t)| t)
+    }
+
+    fn error<T>(&self, msg: &str) -> Result<T, ExprError> {
+        let pos = match self.tokens.get(self.pos) {
+            Some((pos, _)) => *pos,
+            None => self.tokens.last().map(|(pos, _)| pos + 1).unwrap_or(0),
+        };
+        Err(ExprError::Parse {
+            pos,
+            msg: msg.to_owned(),
+        })
+    }
+
+    fn eat_op(&mut self, ops: &[(&'static str, BinOp)]) -> Option<BinOp> {
+        if let Some(Token::Op(op)) = self.peek() {
+            if let Some((_, bin_op)) = ops.iter().find(|(o, _)| o == op) {
+                self.pos += 1;
+                return Some(*bin_op);
+            }
+        }
+        None
+    }
+
+    fn expect_op(&mut self, op: &str) -> Result<(), ExprError> {
+        match self.peek() {
+            Some(Token::Op(o)) if *o == op => {
+                self.pos += 1;
+                Ok(())
+            }
+            _ => self.error(&format!("Expected '{}'", op)),
+        }
+    }
+
+    fn parse_binary(
+        &mut self,
+        ops: &[(&'static str, BinOp)],
+        next: fn(&mut Self) -> Result<Node, ExprError>,
+    ) -> Result<Node, ExprError> {
+        let mut lhs = next(self)?;
+        while let Some(op) = self.eat_op(ops) {
+            let rhs = next(self)?;
+            lhs = Node::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_or(&mut self) -> Result<Node, ExprError> {
+        self.parse_binary(&[("||", BinOp::Or)], Self::parse_and)
+    }
+
+    fn parse_and(&mut self) -> Result<Node, ExprError> {
+        self.parse_binary(&[("&&", BinOp::And)], Self::parse_cmp)
+    }
+
+    fn parse_cmp(&mut self) -> Result<Node, ExprError> {
+        self.parse_binary(
+            &[
+                ("==", BinOp::Eq),
+                ("!=", BinOp::Ne),
+                ("<=", BinOp::Le),
+                (">=", BinOp::Ge),
+                ("<", BinOp::Lt),
+                (">", BinOp::Gt),
+            ],
+            Self::parse_sum,
+        )
+    }
+
+    fn parse_sum(&mut self) -> Result<Node, ExprError> {
+        self.parse_binary(&[("+", BinOp::Add), ("-", BinOp::Sub)], Self::parse_product)
+    }
+
+    fn parse_product(&mut self) -> Result<Node, ExprError> {
+        self.parse_binary(
+            &[("*", BinOp::Mul), ("/", BinOp::Div), ("%", BinOp::Mod)],
+            Self::parse_unary,
+        )
+    }
+
+    fn parse_unary(&mut self) -> Result<Node, ExprError> {
+        let op = match self.peek() {
+            Some(Token::Op("-")) => UnOp::Neg,
+            Some(Token::Op("!")) => UnOp::Not,
+            _ => return self.parse_primary(),
+        };
+        self.pos += 1;
+        Ok(Node::Unary(op, Box::new(self.parse_unary()?)))
+    }
+
+    fn parse_primary(&mut self) -> Result<Node, ExprError> {
+        let token = match self.peek() {
+            Some(token) => token.clone(),
+            None => return self.error("Unexpected end of expression"),
+        };
+        self.pos += 1;
+        match token {
+            Token::Int(i) => Ok(Node::Const(XSMWord::Int(i))),
+            Token::Str(s) => Ok(Node::Const(XSMWord::Str(s))),
+            Token::Op("(") => {
+                let node = self.parse_or()?;
+                self.expect_op(")")?;
+                Ok(node)
+            }
+            Token::Op("[") => self.parse_mem(false),
+            Token::Ident(ref name)
+                if name.eq_ignore_ascii_case("v") && self.peek() == Some(&Token::Op("[")) =>
+            {
+                self.pos += 1;
+                self.parse_mem(true)
+            }
+            Token::Ident(name) => match name.to_ascii_lowercase().as_str() {
+                "mode" => Ok(Node::Mode),
+                "user" | "kernel" => Ok(Node::Const(XSMWord::Str(name.to_ascii_lowercase()))),
+                _ => match Reg::from_name(&name) {
+                    Some(reg) => Ok(Node::Reg(reg)),
+                    None => {
+                        self.pos -= 1;
+                        self.error(&format!("Unknown name '{}'", name))
+                    }
+                },
+            },
+            Token::Op(_) => {
+                self.pos -= 1;
+                self.error("Expected a value")
+            }
+        }
+    }
+
+    /// Called after the opening '['
+    fn parse_mem(&mut self, is_virtual: bool) -> Result<Node, ExprError> {
+        let addr = self.parse_or()?;
+        self.expect_op("]")?;
+        Ok(Node::Mem {
+            addr: Box::new(addr),
+            is_virtual,
+        })
+    }
+}
+
+fn eval_int(node: &Node, ctx: &mut dyn Context) -> Result<i64, ExprError> {
+    match eval(node, ctx)? {
+        XSMWord::Int(i) => Ok(i),
+        XSMWord::Str(s) => Err(ExprError::Eval(format!("\"{}\" is not an integer", s))),
+    }
+}
+
+fn eval(node: &Node, ctx: &mut dyn Context) -> Result<XSMWord, ExprError> {
+    let value = match node {
+        Node::Const(value) => value.clone(),
+        Node::Reg(reg) => reg.get(ctx.regs()).clone(),
+        Node::Mode => XSMWord::Str(if ctx.is_user_mode() { "user" } else { "kernel" }.to_owned()),
+        Node::Mem { addr, is_virtual } => {
+            let addr = eval_int(addr, ctx)?;
+            if addr < 0 {
+                return Err(ExprError::Eval(format!("Negative address {}", addr)));
+            }
+            match ctx.read_word(addr as usize, *is_virtual) {
+                Some(word) => word,
+                None => {
+                    return Err(ExprError::Eval(format!(
+                        "Cannot read {} address {}",
+                        if *is_virtual { "virtual" } else { "physical" },
+                        addr
+                    )))
+                }
+            }
+        }
+        Node::Unary(UnOp::Neg, operand) => XSMWord::Int(eval_int(operand, ctx)?.wrapping_neg()),
+        Node::Unary(UnOp::Not, operand) => XSMWord::Int(!eval(operand, ctx)?.is_true() as i64),
+        Node::Binary(BinOp::And, lhs, rhs) => {
+            XSMWord::Int((eval(lhs, ctx)?.is_true() && eval(rhs, ctx)?.is_true()) as i64)
+        }
+        Node::Binary(BinOp::Or, lhs, rhs) => {
+            XSMWord::Int((eval(lhs, ctx)?.is_true() || eval(rhs, ctx)?.is_true()) as i64)
+        }
+        Node::Binary(BinOp::Eq, lhs, rhs) => {
+            XSMWord::Int((eval(lhs, ctx)? == eval(rhs, ctx)?) as i64)
+        }
+        Node::Binary(BinOp::Ne, lhs, rhs) => {
+            XSMWord::Int((eval(lhs, ctx)? != eval(rhs, ctx)?) as i64)
+        }
+        Node::Binary(op, lhs, rhs) => {
+            let lhs = eval_int(lhs, ctx)?;
+            let rhs = eval_int(rhs, ctx)?;
+            XSMWord::Int(match op {
+                BinOp::Lt => (lhs < rhs) as i64,
+                BinOp::Le => (lhs <= rhs) as i64,
+                BinOp::Gt => (lhs > rhs) as i64,
+                BinOp::Ge => (lhs >= rhs) as i64,
+                BinOp::Add => lhs.wrapping_add(rhs),
+                BinOp::Sub => lhs.wrapping_sub(rhs),
+                BinOp::Mul => lhs.wrapping_mul(rhs),
+                BinOp::Div | BinOp::Mod if rhs == 0 => {
+                    return Err(ExprError::Eval("Division by zero".to_owned()))
+                }
+                BinOp::Div => lhs.wrapping_div(rhs),
+                BinOp::Mod => lhs.wrapping_rem(rhs),
+                BinOp::Or | BinOp::And | BinOp::Eq | BinOp::Ne => unreachable!(),
+            })
+        }
+    };
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    struct TestContext {
+        regs: XSMRegs,
+        is_user: bool,
+        /// Words by address and whether it is virtual
+        memory: HashMap<(usize, bool), XSMWord>,
+    }
+
+    impl Context for TestContext {
+        fn regs(&self) -> &XSMRegs {
+            &self.regs
+        }
+
+        fn is_user_mode(&self) -> bool {
+            self.is_user
+        }
+
+        fn read_word(&mut self, addr: usize, is_virtual: bool) -> Option<XSMWord> {
+            self.memory.get(&(addr, is_virtual)).cloned()
+        }
+    }
+
+    fn context() -> TestContext {
+        let mut regs = XSMRegs::default();
+        regs.r[1] = XSMWord::Int(5);
+        regs.r[19] = XSMWord::Int(-3);
+        regs.p[0] = XSMWord::Str("hello".to_owned());
+        regs.sp = XSMWord::Int(4096);
+        regs.ptbr = XSMWord::Int(28672);
+        let mut memory = HashMap::new();
+        memory.insert((28676, false), XSMWord::Int(-1));
+        memory.insert((4096, true), XSMWord::Int(7));
+        TestContext {
+            regs,
+            is_user: true,
+            memory,
+        }
+    }
+
+    fn eval_str(source: &str) -> Result<XSMWord, ExprError> {
+        Expr::parse(source)?.eval(&mut context())
+    }
+
+    fn int(source: &str) -> i64 {
+        match eval_str(source) {
+            Ok(XSMWord::Int(i)) => i,
+            result => panic!("{}: {:?}", source, result),
+        }
+    }
+
+    fn parse_error_pos(source: &str) -> usize {
+        match Expr::parse(source) {
+            Err(ExprError::Parse { pos, .. }) => pos,
+            result => panic!("{}: {:?}", source, result),
+        }
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(int("2 + 3 * 4"), 14);
+        assert_eq!(int("(2 + 3) * 4"), 20);
+        assert_eq!(int("10 - 4 - 3"), 3);
+        assert_eq!(int("17 / 5 % 2"), 1);
+        assert_eq!(int("-2 * 3"), -6);
+        assert_eq!(int("1 + 1 == 2 && 3 < 2 || 1"), 1);
+        assert_eq!(int("0 || 1 && 0"), 0);
+        assert_eq!(int("!0 == 1"), 1);
+        assert_eq!(int("!(1 == 1)"), 0);
+    }
+
+    #[test]
+    fn register_names_in_any_case() {
+        assert_eq!(int("R1"), 5);
+        assert_eq!(int("r1 + R19"), 2);
+        assert_eq!(int("sp == 4096"), 1);
+        assert_eq!(int("PTBR"), 28672);
+        assert!(Reg::from_name("R20").is_none());
+        assert!(Reg::from_name("P4").is_none());
+        assert_eq!(parse_error_pos("R1 + R20"), 5);
+    }
+
+    #[test]
+    fn mode_and_memory() {
+        assert_eq!(int("mode == user"), 1);
+        assert_eq!(int("MODE != kernel"), 1);
+        assert_eq!(int("[PTBR + 4] == -1"), 1);
+        assert_eq!(int("v[SP]"), 7);
+        assert!(matches!(eval_str("[SP]"), Err(ExprError::Eval(_))));
+        assert!(matches!(eval_str("[0 - 1]"), Err(ExprError::Eval(_))));
+    }
+
+    #[test]
+    fn strings_compare_for_equality_only() {
+        assert_eq!(int("P0 == \"hello\""), 1);
+        assert_eq!(int("P0 != \"hell\""), 1);
+        assert_eq!(int("\"5\" == R1"), 0);
+        assert!(matches!(eval_str("P0 < 5"), Err(ExprError::Eval(_))));
+        assert!(matches!(eval_str("P0 + 1"), Err(ExprError::Eval(_))));
+    }
+
+    #[test]
+    fn division_by_zero_is_an_error() {
+        assert!(matches!(eval_str("R1 / 0"), Err(ExprError::Eval(_))));
+        assert!(matches!(eval_str("R1 % (R1 - 5)"), Err(ExprError::Eval(_))));
+    }
+
+    #[test]
+    fn malformed_input_reports_the_position() {
+        // Past the last token when the input ends too early
+        assert_eq!(parse_error_pos("R1 =="), 4);
+        assert_eq!(parse_error_pos("(R1 + 2"), 7);
+        assert_eq!(parse_error_pos("R1 2"), 3);
+        assert_eq!(parse_error_pos("R1 = 2"), 3);
+        assert_eq!(parse_error_pos("P0 == \"open"), 6);
+        assert_eq!(parse_error_pos("foo == 1"), 0);
+        assert_eq!(parse_error_pos("[R1"), 2);
+        assert_eq!(parse_error_pos("* 2"), 0);
+        assert_eq!(parse_error_pos("99999999999999999999"), 0);
+        assert_eq!(parse_error_pos(""), 0);
+    }
+}
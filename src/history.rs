@@ -0,0 +1,198 @@
+use std::collections::VecDeque;
+
+/// A bounded list of past states, oldest first,
+/// with a cursor for viewing one of them.
+/// The cursor is None while viewing the live state.
+pub struct History<T> {
+    states: VecDeque<T>,
+    capacity: usize,
+    pos: Option<usize>,
+}
+
+impl<T> History<T> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            states: VecDeque::with_capacity(capacity),
+            capacity,
+            pos: None,
+        }
+    }
+
+    /// Drops the oldest state once the history is full.
+    pub fn push(&mut self, state: T) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.states.len() == self.capacity {
+            self.drop_oldest();
+        }
+        self.states.push_back(state);
+    }
+
+    /// If the oldest state is the one being viewed, the live state
+    /// is viewed instead, rather than the state after it.
+    fn drop_oldest(&mut self) {
+        self.states.pop_front();
+        self.pos = self.pos.and_then(|pos| pos.checked_sub(1));
+    }
+
+    pub fn clear(&mut self) {
+        self.states.clear();
+        self.pos = None;
+    }
+
+    pub fn len(&self) -> usize {
+        self.states.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.states.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.states.iter()
+    }
+
+    pub fn last(&self) -> Option<&T> {
+        self.states.back()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn set_capacity(&mut self, capacity: usize) {
+        while self.states.len() > capacity {
+            self.drop_oldest();
+        }
+        self.capacity = capacity;
+    }
+
+    pub fn pos(&self) -> Option<usize> {
+        self.pos
+    }
+
+    /// Views the state at pos, or the live state if pos is None
+    /// or past the end.
+    pub fn seek(&mut self, pos: Option<usize>) {
+        self.pos = pos.filter(|&pos| pos < self.states.len());
+    }
+
+    /// The state being viewed, or None if viewing the live state.
+    pub fn current(&self) -> Option<&T> {
+        self.pos.map(|pos| &self.states[pos])
+    }
+
+    pub fn current_mut(&mut self) -> Option<&mut T> {
+        self.pos.map(move |pos| &mut self.states[pos])
+    }
+
+    /// Moves the cursor one state back. Returns false at the oldest state.
+    pub fn back(&mut self) -> bool {
+        match self.pos {
+            None if !self.states.is_empty() => self.pos = Some(self.states.len() - 1),
+            Some(pos) if pos > 0 => self.pos = Some(pos - 1),
+            _ => return false,
+        }
+        true
+    }
+
+    /// Moves the cursor one state forward. Returns false at the live state.
+    pub fn forward(&mut self) -> bool {
+        match self.pos {
+            Some(pos) => {
+                self.seek(Some(pos + 1));
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn history(states: std::ops::Range<i32>, capacity: usize) -> History<i32> {
+        let mut history = History::new(capacity);
+        for state in states {
+            history.push(state);
+        }
+        history
+    }
+
+    fn states(history: &History<i32>) -> Vec<i32> {
+        history.iter().copied().collect()
+    }
+
+    #[test]
+    fn push_past_capacity_drops_the_oldest_states() {
+        let mut history = history(0..3, 3);
+        history.seek(Some(1));
+        history.push(3);
+        assert_eq!(states(&history), [1, 2, 3]);
+        // The cursor stays on the state it was viewing.
+        assert_eq!(history.current(), Some(&1));
+        assert_eq!(history.pos(), Some(0));
+    }
+
+    #[test]
+    fn pushing_out_the_viewed_state_views_the_live_one() {
+        let mut history = history(0..3, 3);
+        history.seek(Some(0));
+        history.push(3);
+        assert_eq!(states(&history), [1, 2, 3]);
+        assert_eq!(history.pos(), None);
+        assert_eq!(history.current(), None);
+    }
+
+    #[test]
+    fn empty_history_keeps_nothing() {
+        let mut history = history(0..3, 0);
+        assert!(history.is_empty());
+        assert!(!history.back());
+    }
+
+    #[test]
+    fn seek_past_the_end_views_the_live_state() {
+        let mut history = history(0..3, 5);
+        history.seek(Some(2));
+        assert_eq!(history.current(), Some(&2));
+        history.seek(Some(3));
+        assert_eq!(history.pos(), None);
+        assert_eq!(history.current(), None);
+    }
+
+    #[test]
+    fn back_and_forward_stop_at_the_ends() {
+        let mut history = history(0..2, 5);
+        assert!(!history.forward());
+        assert!(history.back());
+        assert_eq!(history.current(), Some(&1));
+        assert!(history.back());
+        assert!(!history.back());
+        assert_eq!(history.current(), Some(&0));
+        assert!(history.forward());
+        assert!(history.forward());
+        assert_eq!(history.pos(), None);
+    }
+
+    #[test]
+    fn shrinking_while_viewing_keeps_the_newest_states() {
+        let mut history = history(0..5, 5);
+        history.seek(Some(3));
+        history.set_capacity(2);
+        assert_eq!(states(&history), [3, 4]);
+        assert_eq!(history.current(), Some(&3));
+        // A viewed state that is dropped gives way to the live one.
+        history.seek(Some(0));
+        history.set_capacity(1);
+        assert_eq!(states(&history), [4]);
+        assert_eq!(history.current(), None);
+        history.set_capacity(0);
+        assert!(history.is_empty());
+        assert_eq!(history.pos(), None);
+        history.push(5);
+        assert!(history.is_empty());
+    }
+}
@@ -0,0 +1,148 @@
+//! How to start a session when xdb is given no command line:
+//! the choices made in the launch dialog, and the recently used ones,
+//! kept in `xdb/recent.json` under the user's config directory.
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::backend::BackendError;
+use crate::emulator;
+use crate::process::{self, SharedFiles, Timeouts};
+use crate::xsm::XSM;
+
+/// Number of recently used configurations kept.
+const MAX_RECENT: usize = 10;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LaunchConfig {
+    /// The xsm binary, unused by the emulator
+    pub xsm: String,
+    /// The disk image, used as `disk.xfs` whatever its name.
+    /// `library.lib` is taken from its folder.
+    pub disk_image: String,
+    pub timer: usize,
+    pub disk: usize,
+    pub console: usize,
+    /// Runs the machine in the built-in emulator instead of xsm
+    pub emulate: bool,
+}
+
+impl Default for LaunchConfig {
+    fn default() -> Self {
+        let timings = emulator::Config::default();
+        LaunchConfig {
+            xsm: "xsm".to_owned(),
+            disk_image: "disk.xfs".to_owned(),
+            timer: timings.timer,
+            disk: timings.disk,
+            console: timings.console,
+            emulate: false,
+        }
+    }
+}
+
+impl LaunchConfig {
+    /// The xsm command line, `--debug` included.
+    pub fn argv(&self) -> Vec<String> {
+        let mut argv = vec![self.xsm.clone(), "--debug".to_owned()];
+        for (option, value) in &[
+            ("--timer", self.timer),
+            ("--disk", self.disk),
+            ("--console", self.console),
+        ] {
+            argv.push((*option).to_owned());
+            argv.push(value.to_string());
+        }
+        argv
+    }
+
+    /// The disk image, and `library.lib` next to it.
+    pub fn shared_files(&self) -> SharedFiles {
+        let disk = PathBuf::from(&self.disk_image);
+        let dir = disk.parent().unwrap_or_else(|| Path::new(""));
+        SharedFiles {
+            library: dir.join("library.lib"),
+            disk,
+        }
+    }
+
+    /// A line describing the configuration in the list of recent ones.
+    pub fn label(&self) -> String {
+        let runner = if self.emulate {
+            "emulator".to_owned()
+        } else {
+            self.xsm.clone()
+        };
+        format!(
+            "{} --timer {} --disk {} --console {}  [{}]",
+            runner, self.timer, self.disk, self.console, self.disk_image
+        )
+    }
+
+    /// The same configuration with a relative disk image, and a relative
+    /// path to xsm, made absolute, so that it can be used from anywhere.
+    /// An xsm without a `/` is looked up in PATH and left as it is.
+    pub fn resolved(&self) -> io::Result<LaunchConfig> {
+        let cwd = env::current_dir()?;
+        let mut config = self.clone();
+        if config.xsm.contains('/') {
+            config.xsm = cwd.join(&config.xsm).to_string_lossy().into_owned();
+        }
+        config.disk_image = cwd.join(&config.disk_image).to_string_lossy().into_owned();
+        Ok(config)
+    }
+
+    /// Starts the session on the disk image and the library next to it.
+    pub fn start(&self, timeouts: Timeouts) -> Result<XSM, BackendError> {
+        let config = self.resolved().map_err(BackendError::WorkDir)?;
+        let mut argv = config.argv();
+        if !config.emulate {
+            return XSM::spawn_with_files(&argv, timeouts, config.shared_files());
+        }
+        argv[0] = "xsm".to_owned();
+        let timings = emulator::Config {
+            disk_file: PathBuf::from(&config.disk_image),
+            timer: config.timer,
+            disk: config.disk,
+            console: config.console,
+        };
+        XSM::emulate(&process::command_line(&argv), timings)
+    }
+}
+
+/// Where the recent configurations are kept: `$XDG_CONFIG_HOME/xdb/recent.json`,
+/// or `~/.config/xdb/recent.json`.
+pub fn recent_path() -> Option<PathBuf> {
+    let config_dir = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(config_dir.join("xdb").join("recent.json"))
+}
+
+/// The recent configurations, most recent first.
+/// A missing or unreadable file gives none.
+pub fn load_recent(path: &Path) -> Vec<LaunchConfig> {
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|text| serde_json::from_str(&text).ok())
+        .unwrap_or_default()
+}
+
+pub fn save_recent(path: &Path, recent: &[LaunchConfig]) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, serde_json::to_string_pretty(recent)?)
+}
+
+/// Moves the configuration to the front of the recent ones.
+pub fn remember(recent: &mut Vec<LaunchConfig>, config: &LaunchConfig) {
+    recent.retain(|other| other != config);
+    recent.insert(0, config.clone());
+    recent.truncate(MAX_RECENT);
+}
@@ -0,0 +1,169 @@
+use std::convert::TryInto;
+use std::path::{Path, PathBuf};
+
+use imgui::{Condition, ImString, Ui};
+
+use xdb::launch::{self, LaunchConfig};
+use xdb::process::Timeouts;
+use xdb::xsm::XSM;
+
+/// The dialog shown when xdb is started without a command line.
+pub struct Launcher {
+    xsm: ImString,
+    disk_image: ImString,
+    timer: i32,
+    disk: i32,
+    console: i32,
+    emulate: bool,
+    timeouts: Timeouts,
+    recent: Vec<LaunchConfig>,
+    recent_path: Option<PathBuf>,
+    error: Option<String>,
+    /// A session held back until the error about saving the recent
+    /// configurations has been seen
+    held: Option<XSM>,
+}
+
+impl Launcher {
+    /// The form starts with the most recently used configuration.
+    pub fn new(timeouts: Timeouts) -> Self {
+        let recent_path = launch::recent_path();
+        let recent = recent_path
+            .as_deref()
+            .map(launch::load_recent)
+            .unwrap_or_default();
+        let mut launcher = Self {
+            xsm: ImString::with_capacity(1024),
+            disk_image: ImString::with_capacity(1024),
+            timer: 0,
+            disk: 0,
+            console: 0,
+            emulate: false,
+            timeouts,
+            recent,
+            recent_path,
+            error: None,
+            held: None,
+        };
+        let config = launcher.recent.first().cloned().unwrap_or_default();
+        launcher.fill(&config);
+        launcher
+    }
+
+    fn fill(&mut self, config: &LaunchConfig) {
+        self.xsm.clear();
+        self.xsm.push_str(&config.xsm);
+        self.disk_image.clear();
+        self.disk_image.push_str(&config.disk_image);
+        self.timer = config.timer as i32;
+        self.disk = config.disk as i32;
+        self.console = config.console as i32;
+        self.emulate = config.emulate;
+    }
+
+    fn config(&self) -> LaunchConfig {
+        LaunchConfig {
+            xsm: self.xsm.to_str().trim().to_owned(),
+            disk_image: self.disk_image.to_str().trim().to_owned(),
+            timer: self.timer.try_into().unwrap_or(0),
+            disk: self.disk.try_into().unwrap_or(0),
+            console: self.console.try_into().unwrap_or(0),
+            emulate: self.emulate,
+        }
+    }
+
+    /// Returns the session once it has been started.
+    pub fn render(&mut self, ui: &mut Ui) -> Option<XSM> {
+        let mut started = None;
+        imgui::Window::new(im_str!("Launch"))
+            .size([600.0, 400.0], Condition::FirstUseEver)
+            .build(ui, || {
+                ui.push_item_width(350.0);
+                ui.input_text(im_str!("xsm binary"), &mut self.xsm).build();
+                let xsm = self.xsm.to_str().trim();
+                if self.emulate {
+                    ui.text_disabled(im_str!("Not used by the emulator"));
+                } else if !xsm.contains('/') {
+                    ui.text_disabled(im_str!("Looked up in PATH"));
+                } else if !Path::new(xsm).is_file() {
+                    ui.text(im_str!("Not found"));
+                }
+                ui.input_text(im_str!("Disk image"), &mut self.disk_image)
+                    .build();
+                if !Path::new(self.disk_image.to_str().trim()).is_file() {
+                    ui.text(im_str!("Not found"));
+                }
+                ui.text_disabled(im_str!(
+                    "xsm is run from the folder of the disk image, with library.lib next to it."
+                ));
+                ui.checkbox(im_str!("Built-in emulator"), &mut self.emulate);
+
+                ui.push_item_width(100.0);
+                ui.input_int(im_str!("--timer"), &mut self.timer).build();
+                ui.input_int(im_str!("--disk"), &mut self.disk).build();
+                ui.input_int(im_str!("--console"), &mut self.console)
+                    .build();
+
+                if self.held.is_some() {
+                    if ui.button(im_str!("Continue"), [0.0, 0.0]) {
+                        self.error = None;
+                        started = self.held.take();
+                    }
+                } else if ui.button(im_str!("Start"), [0.0, 0.0]) {
+                    started = self.start();
+                }
+                if let Some(error) = &self.error {
+                    ui.text_wrapped(&im_str!("{}", error));
+                }
+
+                ui.separator();
+                ui.text(im_str!("Recently used"));
+                let mut chosen = None;
+                for (i, config) in self.recent.iter().enumerate() {
+                    if ui.button(&im_str!("Use##recent{}", i), [0.0, 0.0]) {
+                        chosen = Some(config.clone());
+                    }
+                    ui.same_line(0.0);
+                    ui.text(config.label());
+                }
+                if let Some(config) = chosen {
+                    self.fill(&config);
+                }
+            });
+        started
+    }
+
+    /// Shows an error about a session that could not be set up
+    /// after the launcher handed it over.
+    pub fn set_error(&mut self, error: String) {
+        self.error = Some(error);
+    }
+
+    /// Remembers the configuration once the session has started.
+    fn start(&mut self) -> Option<XSM> {
+        let config = match self.config().resolved() {
+            Ok(config) => config,
+            Err(e) => {
+                self.error = Some(format!("Failed to resolve paths: {}", e));
+                return None;
+            }
+        };
+        let xsm = match config.start(self.timeouts) {
+            Ok(xsm) => xsm,
+            Err(e) => {
+                self.error = Some(e.to_string());
+                return None;
+            }
+        };
+        self.error = None;
+        launch::remember(&mut self.recent, &config);
+        if let Some(path) = &self.recent_path {
+            if let Err(e) = launch::save_recent(path, &self.recent) {
+                self.error = Some(format!("Failed to save recent configurations: {}", e));
+                self.held = Some(xsm);
+                return None;
+            }
+        }
+        Some(xsm)
+    }
+}
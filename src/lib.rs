@@ -0,0 +1,21 @@
+//! The debugger engine of xdb, without the user interface.
+
+#![allow(clippy::upper_case_acronyms)]
+#![allow(clippy::result_unit_err)]
+
+#[macro_use]
+extern crate try_or;
+
+pub mod backend;
+pub mod emulator;
+pub mod expr;
+pub mod history;
+pub mod launch;
+pub mod parse;
+pub mod process;
+pub mod progress;
+pub mod replay;
+pub mod trace;
+pub mod word;
+pub mod worker;
+pub mod xsm;
@@ -1,33 +1,181 @@
-#![allow(dead_code)]
+#![allow(clippy::upper_case_acronyms)]
 
 #[macro_use]
 extern crate imgui;
-#[macro_use]
-extern crate try_or;
 
-use xsm::XSM;
+use std::path::Path;
+use std::time::Duration;
 
-mod xsm;
+use xdb::emulator;
+use xdb::process::{self, Timeouts};
+use xdb::xsm::XSM;
 
+mod launcher;
 mod ui;
 mod ui_support;
 
 fn main() {
-    let args :Vec<String> = std::env::args().collect();
-    if args.len() <= 1 {
-        println!("XDB: Visual debugger for XSM");
-        println!("Syntax:");
-        println!("xdb <xsm command line>");
-        println!();
-        println!("Example: ");
-        println!("xdb xsm --debug --timer 100");
+    let mut args = std::env::args().skip(1).peekable();
+    let mut trace_file = None;
+    let mut replay_file = None;
+    let mut input_file = None;
+    let mut emulate = false;
+    let mut timeouts = Timeouts::default();
+    // Options for xdb come before the xsm command line.
+    while let Some(arg) = args.peek() {
+        match arg.as_str() {
+            "--trace" => {
+                args.next();
+                match args.next() {
+                    Some(file) => trace_file = Some(file),
+                    None => {
+                        println!("Error: --trace takes a file name");
+                        return;
+                    }
+                }
+            }
+            "--emulate" => {
+                args.next();
+                emulate = true;
+            }
+            "--replay" => {
+                args.next();
+                match args.next() {
+                    Some(file) => replay_file = Some(file),
+                    None => {
+                        println!("Error: --replay takes a file name");
+                        return;
+                    }
+                }
+            }
+            "--input" => {
+                args.next();
+                match args.next() {
+                    Some(file) => input_file = Some(file),
+                    None => {
+                        println!("Error: --input takes a file name");
+                        return;
+                    }
+                }
+            }
+            "--help" | "-h" => {
+                print_usage();
+                return;
+            }
+            "--timeout" => {
+                args.next();
+                match args.next().and_then(|secs| secs.parse().ok()) {
+                    Some(secs) => timeouts.response = Duration::from_secs(secs),
+                    None => {
+                        println!("Error: --timeout takes a number of seconds");
+                        return;
+                    }
+                }
+            }
+            _ => break,
+        }
+    }
+    let args: Vec<String> = args.collect();
+    if replay_file.is_some() && (trace_file.is_some() || input_file.is_some()) {
+        println!("Error: --replay cannot be combined with --trace or --input");
+        return;
+    }
+    // Lines of console input, written each time the machine waits for one.
+    let input_lines: Vec<String> = match &input_file {
+        Some(input_file) => match std::fs::read_to_string(input_file) {
+            Ok(text) => text.lines().map(str::to_owned).collect(),
+            Err(e) => {
+                println!("Error: Failed to read input file {}: {}", input_file, e);
+                return;
+            }
+        },
+        None => Vec::new(),
+    };
+    let trace_file = trace_file.as_deref();
+    if replay_file.is_none() && !emulate && args.is_empty() {
+        // Without a command line, the session is chosen in a dialog.
+        let mut launcher = launcher::Launcher::new(timeouts);
+        let mut xsm_ui: Option<ui::UI> = None;
+        let sys = ui_support::init("XDB - Visual Debugger for eXpOS");
+        sys.main_loop(|_, ui| match &mut xsm_ui {
+            Some(xsm_ui) => xsm_ui.render_all(ui),
+            None => {
+                if let Some(xsm) = launcher.render(ui) {
+                    match debug_ui(xsm, trace_file, &input_lines) {
+                        Ok(started) => xsm_ui = Some(started),
+                        Err(e) => launcher.set_error(e),
+                    }
+                }
+            }
+        });
         return;
     }
-    let command_line = args.iter().skip(1).fold(String::new(), |acc, x| {
-        acc + " " + x
-    });
-    let xsm = try_or!(XSM::spawn_new(&command_line), ());
-    let mut xsm_ui = ui::UI::new(xsm);
+    let started = open(replay_file.as_deref(), emulate, &args, timeouts)
+        .and_then(|xsm| debug_ui(xsm, trace_file, &input_lines));
+    let mut xsm_ui = match started {
+        Ok(xsm_ui) => xsm_ui,
+        Err(e) => {
+            println!("Error: {}", e);
+            return;
+        }
+    };
     let sys = ui_support::init("XDB - Visual Debugger for eXpOS");
     sys.main_loop(|_, ui| xsm_ui.render_all(ui));
 }
+
+/// Starts the session given on the command line.
+fn open(
+    replay_file: Option<&str>,
+    emulate: bool,
+    args: &[String],
+    timeouts: Timeouts,
+) -> Result<XSM, String> {
+    if let Some(replay_file) = replay_file {
+        return XSM::open_trace(Path::new(replay_file)).map_err(|e| e.to_string());
+    }
+    if emulate {
+        let config = emulator::Config::from_args(args).map_err(|e| e.to_string())?;
+        let mut argv = vec!["xsm".to_owned()];
+        argv.extend(args.iter().cloned());
+        return XSM::emulate(&process::command_line(&argv), config).map_err(|e| e.to_string());
+    }
+    XSM::spawn_with_timeouts(args, timeouts).map_err(|e| e.to_string())
+}
+
+/// The debugger window for a session, which is traced and given the
+/// console input from the command line however it was started.
+fn debug_ui(
+    mut xsm: XSM,
+    trace_file: Option<&str>,
+    input_lines: &[String],
+) -> Result<ui::UI, String> {
+    if let Some(trace_file) = trace_file {
+        xsm.start_trace(Path::new(trace_file))
+            .map_err(|e| format!("Failed to create trace file {}: {}", trace_file, e))?;
+    }
+    for line in input_lines {
+        xsm.queue_input(line);
+    }
+    Ok(ui::UI::new(xsm))
+}
+
+fn print_usage() {
+    println!("XDB: Visual debugger for XSM");
+    println!("Syntax:");
+    println!("xdb                (opens a dialog to start a session)");
+    println!("xdb [--trace <file>] [--input <file>] [--timeout <seconds>] <xsm command line>");
+    println!("xdb --replay <file>");
+    println!("xdb [--trace <file>] [--input <file>] --emulate [xsm options]");
+    println!();
+    println!("Options:");
+    println!("  --trace <file>  Record every executed instruction to file (JSON Lines)");
+    println!("  --input <file>  Console input, one line each time the machine waits for one");
+    println!("  --timeout <seconds>");
+    println!("                  How long to wait for xsm to respond to a command (default 10)");
+    println!("  --replay <file> View a recorded trace instead of running xsm");
+    println!("  --emulate       Run the machine inside xdb instead of the xsm binary;");
+    println!("                  takes xsm's --timer, --disk and --console options");
+    println!();
+    println!("Example: ");
+    println!("xdb xsm --debug --timer 100");
+}
@@ -0,0 +1,210 @@
+//! Parsing of what xsm prints in debug mode.
+//! Lines are taken as read from xsm, with their line endings
+//! and with the `debug> ` prompt in front of the first line of a response.
+
+use std::fmt;
+
+use crate::backend::{End, Status, Stop};
+use crate::word::XSMWord;
+use crate::xsm::{Mode, XSMRegs};
+
+/// Number of registers printed by the `reg` command.
+const NUM_REGS: usize = 33;
+pub const PAGE_LEN: usize = 512;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    /// Fewer lines or values than expected
+    Truncated { expected: usize, found: usize },
+    /// The mode line does not say KERNEL or USER
+    BadMode(String),
+    /// The next instruction line has no IP
+    BadStatus(String),
+    /// A word of the `reg` output is not `NAME: value`
+    BadRegister(String),
+    /// A line of the `mem` file is not `address: word`
+    BadMemLine(String),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::Truncated { expected, found } => {
+                write!(f, "Expected {} from xsm, got {}", expected, found)
+            }
+            ParseError::BadMode(line) => write!(f, "Unexpected mode line: '{}'", line),
+            ParseError::BadStatus(line) => write!(f, "No IP in status line: '{}'", line),
+            ParseError::BadRegister(word) => write!(f, "Unexpected register: '{}'", word),
+            ParseError::BadMemLine(line) => write!(f, "Unexpected line in mem file: '{}'", line),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+fn strip_prompt(line: &str) -> &str {
+    line.trim_start_matches("debug> ")
+}
+
+fn strip_newline(line: &str) -> &str {
+    line.trim_end_matches(&['\r', '\n'][..])
+}
+
+/// The opcode of an instruction as shown in the status, such as "INI".
+pub fn opcode(instr: &str) -> &str {
+    instr.split_whitespace().next().unwrap_or("")
+}
+
+/// The instruction executed last, from the status text,
+/// whose first line is `Previous instruction at IP = <ip>: <instr>`.
+pub fn previous_instr(status_text: &str) -> &str {
+    status_text
+        .lines()
+        .next()
+        .and_then(|line| line.split_once(": "))
+        .map_or("", |(_, instr)| instr.trim())
+}
+
+pub fn is_halt_message(line: &str) -> bool {
+    strip_prompt(line).starts_with("Machine is halting.")
+}
+
+/// xsm prints `Watchpoint at memory location <addr> triggered`
+/// before the status when a watched address is written to.
+/// Returns the address.
+fn parse_watch_message(line: &str) -> Option<usize> {
+    strip_newline(strip_prompt(line))
+        .strip_prefix("Watchpoint at memory location ")
+        .and_then(|rest| rest.strip_suffix(" triggered"))
+        .and_then(|addr| addr.parse().ok())
+}
+
+/// Program output among lines: all of them but the watchpoint messages
+/// and the halt message.
+pub fn parse_output(lines: &[String]) -> Vec<String> {
+    lines
+        .iter()
+        .filter(|line| parse_watch_message(line).is_none() && !is_halt_message(line))
+        .map(|line| strip_newline(strip_prompt(line)).to_owned())
+        .collect()
+}
+
+/// Parses the lines xsm prints up to entering debug mode:
+/// program output, watchpoint messages and the three status lines,
+/// or the output up to the halt message.
+pub fn parse_stop(lines: &[String]) -> Result<Stop, ParseError> {
+    let mut stop = Stop::default();
+    let halt = lines.iter().position(|line| is_halt_message(line));
+    let status_start = match halt {
+        Some(halt) => {
+            stop.end = Some(End::Halted);
+            halt
+        }
+        None if lines.len() < 3 => {
+            return Err(ParseError::Truncated {
+                expected: 3,
+                found: lines.len(),
+            })
+        }
+        None => lines.len() - 3,
+    };
+
+    let before = &lines[..status_start];
+    stop.watch_addr = before.iter().find_map(|line| parse_watch_message(line));
+    stop.output = parse_output(before);
+    if stop.end.is_some() {
+        return Ok(stop);
+    }
+
+    let status = &lines[status_start..];
+    let mode_line = strip_prompt(&status[1]);
+    let mode = match mode_line
+        .strip_prefix("Mode: ")
+        .and_then(|m| m.chars().next())
+    {
+        Some('K') => Mode::Kernel,
+        Some('U') => Mode::User,
+        _ => return Err(ParseError::BadMode(strip_newline(mode_line).to_owned())),
+    };
+
+    let next_instr_line = strip_newline(&status[2]);
+    let ip = next_instr_line
+        .split("IP = ")
+        .nth(1)
+        .and_then(|rest| {
+            let end = rest
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len());
+            rest[..end].parse().ok()
+        })
+        .ok_or_else(|| ParseError::BadStatus(next_instr_line.to_owned()))?;
+    // The instruction may itself contain ": " in a string literal.
+    let next_instr = next_instr_line
+        .split_once(": ")
+        .map(|(_, instr)| instr)
+        .unwrap_or("")
+        .trim()
+        .to_owned();
+
+    let mut text = strip_prompt(&status[0]).to_owned();
+    text.push_str(&status[1]);
+    text.push_str(&status[2]);
+    stop.status = Some(Status {
+        text,
+        mode,
+        ip,
+        next_instr,
+    });
+    Ok(stop)
+}
+
+/// Parses the output of the `reg` command:
+/// tab separated `NAME: value` words over several lines.
+pub fn parse_regs(lines: &[String]) -> Result<XSMRegs, ParseError> {
+    let mut regs = XSMRegs::default();
+    let mut found = 0;
+    for line in lines {
+        for word in strip_newline(strip_prompt(line)).split('\t') {
+            if word.trim().is_empty() {
+                continue;
+            }
+            let (name, value) = word
+                .split_once(": ")
+                .or_else(|| word.strip_suffix(':').map(|name| (name, "")))
+                .ok_or_else(|| ParseError::BadRegister(word.to_owned()))?;
+            let reg = regs
+                .get_mut(name.trim())
+                .ok_or_else(|| ParseError::BadRegister(word.to_owned()))?;
+            *reg = XSMWord::parse(value);
+            found += 1;
+        }
+    }
+    if found < NUM_REGS {
+        return Err(ParseError::Truncated {
+            expected: NUM_REGS,
+            found,
+        });
+    }
+    Ok(regs)
+}
+
+/// Parses the `mem` file written by the `mem` command:
+/// one `address: word` line for each word of the page.
+pub fn parse_mem_page(text: &str) -> Result<Vec<XSMWord>, ParseError> {
+    let words = text
+        .lines()
+        .map(|line| {
+            let (_addr, word) = line
+                .split_once(':')
+                .ok_or_else(|| ParseError::BadMemLine(line.to_owned()))?;
+            Ok(XSMWord::parse(word.strip_prefix(' ').unwrap_or(word)))
+        })
+        .collect::<Result<Vec<XSMWord>, ParseError>>()?;
+    if words.len() < PAGE_LEN {
+        return Err(ParseError::Truncated {
+            expected: PAGE_LEN,
+            found: words.len(),
+        });
+    }
+    Ok(words)
+}
@@ -0,0 +1,695 @@
+//! An xsm child process in debug mode, driven through a pseudo-terminal.
+//!
+//! xsm's C library buffers its output unless it is writing to a terminal,
+//! so xsm's stdin, stdout and stderr are the slave end of a PTY.
+//! Echo and output processing are turned off, so that the master end
+//! reads exactly what xsm prints.
+//!
+//! xsm prints `debug> ` without a newline whenever it waits for a command,
+//! so each response is framed by the prompt that follows it.
+//! A response that does not end in a prompt in time is reported as a timeout.
+//!
+//! Each child runs in a temporary directory of its own, since xsm writes
+//! the `mem` file to its working directory. Sessions started side by side
+//! in the same folder thus do not overwrite each other's dumps.
+
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::fs::symlink;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender, TryRecvError};
+
+use crate::backend::{BackendError, DebugBackend, End, Stop};
+use crate::parse::{self, ParseError};
+use crate::word::XSMWord;
+use crate::xsm::XSMRegs;
+
+const PROMPT: &[u8] = b"debug> ";
+/// Lines printed by the `reg` command.
+const REG_LINES: usize = 7;
+
+/// How long to wait for xsm before reporting a timeout.
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    /// For the first prompt after launching xsm
+    pub start: Duration,
+    /// For the response to a command, steps included
+    pub response: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            start: Duration::from_secs(5),
+            response: Duration::from_secs(10),
+        }
+    }
+}
+
+/// The files xsm opens relative to its working directory. They are linked
+/// into the work dir under the names xsm expects, so that xsm reads and
+/// writes these instead.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SharedFiles {
+    /// Linked as `disk.xfs`
+    pub disk: PathBuf,
+    /// Linked as `library.lib`
+    pub library: PathBuf,
+}
+
+impl SharedFiles {
+    /// `disk.xfs` and `library.lib` in `dir`.
+    pub fn in_dir(dir: &Path) -> SharedFiles {
+        SharedFiles {
+            disk: dir.join("disk.xfs"),
+            library: dir.join("library.lib"),
+        }
+    }
+
+    /// The ones in the directory xdb was started from.
+    pub fn current() -> io::Result<SharedFiles> {
+        Ok(SharedFiles::in_dir(&env::current_dir()?))
+    }
+}
+
+/// What the reader thread makes of xsm's output.
+/// The channel is disconnected once xsm closes it.
+enum Event {
+    Line(String),
+    Prompt,
+}
+
+/// The lines of one response and how it ended.
+struct Response {
+    lines: Vec<String>,
+    /// True if xsm closed its output instead of printing the prompt
+    closed: bool,
+}
+
+pub struct XSMProcess {
+    argv: Vec<String>,
+    /// The command line as shown in error messages
+    command: String,
+    timeouts: Timeouts,
+    files: SharedFiles,
+    xsm: Child,
+    /// Master end of the PTY, written to for xsm's stdin
+    pty: File,
+    work_dir: WorkDir,
+    stdout: Receiver<Event>,
+    /// The response to launching xsm, until `initial_stop` takes it
+    initial: Vec<String>,
+    /// Lines read while the machine is running
+    pending: Vec<String>,
+    /// Responses of commands that timed out and are still to arrive.
+    /// They are skipped so that later commands get their own responses.
+    late: usize,
+    /// The instruction at IP, as of the last status
+    next_instr: String,
+    /// True while xsm has watchpoints set
+    watching: bool,
+}
+
+impl XSMProcess {
+    /// `argv` is the xsm program followed by its arguments, passed as they are
+    /// except for relative paths, which are made absolute.
+    /// xsm uses the shared files of the current directory.
+    pub fn spawn(argv: &[String], timeouts: Timeouts) -> Result<XSMProcess, BackendError> {
+        let files = SharedFiles::current().map_err(BackendError::WorkDir)?;
+        XSMProcess::spawn_with_files(argv, timeouts, files)
+    }
+
+    /// Like `spawn`, with `files` in place of those of the current directory.
+    pub fn spawn_with_files(
+        argv: &[String],
+        timeouts: Timeouts,
+        files: SharedFiles,
+    ) -> Result<XSMProcess, BackendError> {
+        let command = command_line(argv);
+        let argv = resolve_paths(argv).map_err(BackendError::WorkDir)?;
+        let (program, args) = argv.split_first().ok_or(BackendError::NoCommand)?;
+        let work_dir = WorkDir::create(&files).map_err(BackendError::WorkDir)?;
+        let (pty, slave) = open_pty().map_err(BackendError::Pty)?;
+        let slave_out = slave.try_clone().map_err(BackendError::Pty)?;
+        let slave_err = slave.try_clone().map_err(BackendError::Pty)?;
+
+        // The Command holding the slave end is dropped right away,
+        // so that reading the master fails once xsm has exited.
+        let xsm_process = Command::new(program)
+            .args(args)
+            .current_dir(&work_dir.path)
+            .stdin(Stdio::from(slave))
+            .stdout(Stdio::from(slave_out))
+            .stderr(Stdio::from(slave_err))
+            .spawn()
+            .map_err(|source| match source.kind() {
+                io::ErrorKind::NotFound => BackendError::NotFound {
+                    program: program.clone(),
+                },
+                _ => BackendError::Launch {
+                    command: command.clone(),
+                    source,
+                },
+            })?;
+
+        let reader = pty.try_clone().map_err(BackendError::Pty)?;
+        let (stdout_tx, stdout_rx) = crossbeam_channel::bounded(100);
+        std::thread::spawn(move || read_events(reader, stdout_tx));
+
+        let mut process = XSMProcess {
+            argv,
+            command,
+            timeouts,
+            files,
+            xsm: xsm_process,
+            pty,
+            work_dir,
+            stdout: stdout_rx,
+            initial: Vec::new(),
+            pending: Vec::new(),
+            late: 0,
+            next_instr: String::new(),
+            watching: false,
+        };
+
+        let response = process.read_response("start", timeouts.start)?;
+        if response.closed {
+            return Err(BackendError::NotInDebugMode {
+                command: process.command.clone(),
+                status: process.wait_exit()?,
+                output: response.lines,
+            });
+        }
+        process.initial = response.lines;
+        Ok(process)
+    }
+
+    /// Writing fails once xsm has exited, which is reported instead.
+    fn send(&mut self, command: std::fmt::Arguments) -> Result<(), BackendError> {
+        writeln!(self.pty, "{}", command).map_err(|e| match self.exit_status() {
+            Some(status) => BackendError::Exited(status),
+            None => BackendError::Send(e),
+        })
+    }
+
+    /// Sends a command and reads its response.
+    fn command(&mut self, command: std::fmt::Arguments) -> Result<Response, BackendError> {
+        self.send(command)?;
+        self.read_response(&command.to_string(), self.timeouts.response)
+    }
+
+    /// Sends a command that prints nothing of interest.
+    fn quiet_command(&mut self, command: std::fmt::Arguments) -> Result<(), BackendError> {
+        let response = self.command(command)?;
+        self.check_open(&response)
+    }
+
+    /// Only step and continue may end with xsm exiting.
+    fn check_open(&mut self, response: &Response) -> Result<(), BackendError> {
+        if response.closed {
+            return Err(BackendError::Exited(self.wait_exit()?));
+        }
+        Ok(())
+    }
+
+    /// Returns the exit status once the xsm child has exited.
+    fn exit_status(&mut self) -> Option<ExitStatus> {
+        self.xsm.try_wait().ok().flatten()
+    }
+
+    /// Waits for xsm to exit after it closed its output,
+    /// killing it if it has not within the start timeout.
+    fn wait_exit(&mut self) -> Result<ExitStatus, BackendError> {
+        let deadline = Instant::now() + self.timeouts.start;
+        while Instant::now() < deadline {
+            if let Some(status) = self.exit_status() {
+                return Ok(status);
+            }
+            sleep(Duration::from_millis(10));
+        }
+        let _ = self.xsm.kill();
+        self.xsm.wait().map_err(BackendError::Wait)
+    }
+
+    /// Reads lines up to the next prompt, after skipping the responses
+    /// of commands that timed out. On a timeout, the response is counted
+    /// as late and the lines read so far are dropped.
+    fn read_response(
+        &mut self,
+        command: &str,
+        timeout: Duration,
+    ) -> Result<Response, BackendError> {
+        let deadline = Instant::now() + timeout;
+        let mut lines = Vec::new();
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            match self.stdout.recv_timeout(left) {
+                Ok(Event::Line(line)) => lines.push(line),
+                Ok(Event::Prompt) if self.late > 0 => {
+                    self.late -= 1;
+                    lines.clear();
+                }
+                Ok(Event::Prompt) => {
+                    return Ok(Response {
+                        lines,
+                        closed: false,
+                    })
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    self.late += 1;
+                    return Err(BackendError::Timeout {
+                        command: command.to_owned(),
+                        timeout,
+                    });
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    self.late = 0;
+                    return Ok(Response {
+                        lines,
+                        closed: true,
+                    });
+                }
+            }
+        }
+    }
+
+    /// Parse errors are reported in the stop instead of the status.
+    fn parse_stop(&mut self, lines: &[String], closed: bool) -> Stop {
+        if closed {
+            return self.final_stop(lines);
+        }
+        let stop = parse::parse_stop(lines).unwrap_or_else(|e| Stop {
+            error: Some(e.into()),
+            ..Stop::default()
+        });
+        if let Some(status) = &stop.status {
+            self.next_instr = status.next_instr.clone();
+        }
+        stop
+    }
+
+    /// xsm closing its output means that it has exited: after halting
+    /// the machine if it printed the halt message, crashed or killed otherwise.
+    /// Whatever it printed last is kept as output.
+    /// If how it exited cannot be found out, the error is reported instead.
+    fn final_stop(&mut self, lines: &[String]) -> Stop {
+        let output = parse::parse_output(lines);
+        if lines.iter().any(|line| parse::is_halt_message(line)) {
+            return Stop {
+                output,
+                end: Some(End::Halted),
+                ..Stop::default()
+            };
+        }
+        match self.wait_exit() {
+            Ok(status) => Stop {
+                output,
+                end: Some(End::of_exit(status)),
+                ..Stop::default()
+            },
+            Err(e) => Stop {
+                output,
+                error: Some(e),
+                ..Stop::default()
+            },
+        }
+    }
+}
+
+/// A temporary directory for one xsm child to run in, removed when dropped.
+struct WorkDir {
+    path: PathBuf,
+}
+
+impl WorkDir {
+    fn create(files: &SharedFiles) -> io::Result<WorkDir> {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let cwd = env::current_dir()?;
+        let path = loop {
+            let n = NEXT.fetch_add(1, Ordering::Relaxed);
+            let path = env::temp_dir().join(format!("xdb-{}-{}", std::process::id(), n));
+            match fs::create_dir(&path) {
+                Ok(()) => break path,
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        };
+        let work_dir = WorkDir { path };
+        for (name, file) in &[("disk.xfs", &files.disk), ("library.lib", &files.library)] {
+            let file = cwd.join(file);
+            if file.exists() {
+                symlink(&file, work_dir.path.join(name))?;
+            }
+        }
+        Ok(work_dir)
+    }
+}
+
+impl Drop for WorkDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Makes the relative paths among the program and its arguments absolute,
+/// as xsm runs in its work dir rather than the current one.
+/// Only arguments with a `/`, and `.xfs` and `.lib` files, are taken as
+/// paths, so that option values such as `--timer 100` are left as they are
+/// even if a file of that name exists. A program without a `/` is looked
+/// up in PATH and left as it is.
+fn resolve_paths(argv: &[String]) -> io::Result<Vec<String>> {
+    let cwd = env::current_dir()?;
+    Ok(argv
+        .iter()
+        .enumerate()
+        .map(|(i, arg)| {
+            let is_path =
+                arg.contains('/') || (i > 0 && (arg.ends_with(".xfs") || arg.ends_with(".lib")));
+            if is_path && Path::new(arg).is_relative() {
+                cwd.join(arg).to_string_lossy().into_owned()
+            } else {
+                arg.clone()
+            }
+        })
+        .collect())
+}
+
+/// Quotes the arguments that need it, for showing a command line to the user.
+pub fn command_line(argv: &[String]) -> String {
+    argv.iter()
+        .map(|arg| {
+            if !arg.is_empty() && !arg.contains(|c: char| c.is_whitespace() || "'\"\\$".contains(c))
+            {
+                arg.clone()
+            } else {
+                format!("'{}'", arg.replace('\'', "'\\''"))
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Splits a command line typed by the user into arguments, as a shell would
+/// for words in single or double quotes and characters escaped with `\`.
+/// The inverse of `command_line`.
+pub fn split_command_line(line: &str) -> Vec<String> {
+    let mut argv = Vec::new();
+    let mut arg = String::new();
+    let mut in_arg = false;
+    let mut quote = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some('"'), '\\') | (None, '\\') => {
+                if let Some(c) = chars.next() {
+                    arg.push(c);
+                }
+            }
+            (Some(_), c) => arg.push(c),
+            (None, '\'') | (None, '"') => quote = Some(c),
+            (None, c) if c.is_whitespace() => {
+                if in_arg {
+                    argv.push(std::mem::take(&mut arg));
+                    in_arg = false;
+                }
+                continue;
+            }
+            (None, c) => arg.push(c),
+        }
+        in_arg = true;
+    }
+    if in_arg {
+        argv.push(arg);
+    }
+    argv
+}
+
+/// Opens a PTY in which what is written to one end comes out
+/// unchanged at the other, returning its master and slave ends.
+fn open_pty() -> io::Result<(File, File)> {
+    let mut master = 0;
+    let mut slave = 0;
+    // SAFETY: openpty only writes the two descriptors; the name,
+    // terminal settings and window size are optional.
+    let ret = unsafe {
+        libc::openpty(
+            &mut master,
+            &mut slave,
+            ptr::null_mut(),
+            ptr::null(),
+            ptr::null(),
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: both descriptors were just opened and are owned by nothing else.
+    let (master, slave) = unsafe { (File::from_raw_fd(master), File::from_raw_fd(slave)) };
+    // SAFETY: the descriptors are valid and termios is filled in by tcgetattr.
+    unsafe {
+        // Only the slave end is meant for xsm, as its standard streams.
+        for fd in &[master.as_raw_fd(), slave.as_raw_fd()] {
+            if libc::fcntl(*fd, libc::F_SETFD, libc::FD_CLOEXEC) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        let mut termios = std::mem::zeroed::<libc::termios>();
+        if libc::tcgetattr(slave.as_raw_fd(), &mut termios) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Without echo, commands sent to xsm are not read back as output.
+        // Without output processing, lines end in \n rather than \r\n.
+        termios.c_lflag &= !(libc::ECHO | libc::ECHONL);
+        termios.c_oflag &= !libc::OPOST;
+        if libc::tcsetattr(slave.as_raw_fd(), libc::TCSANOW, &termios) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok((master, slave))
+}
+
+/// Splits xsm's output into lines and prompts.
+/// The prompt is recognised at the start of a line, where xsm prints it.
+/// Reading the master fails with EIO rather than returning EOF
+/// once xsm has exited, which ends the events all the same.
+fn read_events(pty: impl Read, tx: Sender<Event>) {
+    let mut reader = BufReader::new(pty);
+    let mut line = Vec::new();
+    loop {
+        let buf = match reader.fill_buf() {
+            Ok([]) => break,
+            Ok(buf) => buf,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(_) => break,
+        };
+        let len = buf.len();
+        for &byte in buf {
+            line.push(byte);
+            let event = if byte == b'\n' {
+                Event::Line(String::from_utf8_lossy(&line).into_owned())
+            } else if line == PROMPT {
+                Event::Prompt
+            } else {
+                continue;
+            };
+            line.clear();
+            if tx.send(event).is_err() {
+                return;
+            }
+        }
+        reader.consume(len);
+    }
+    if !line.is_empty() {
+        let _ = tx.send(Event::Line(String::from_utf8_lossy(&line).into_owned()));
+    }
+}
+
+impl DebugBackend for XSMProcess {
+    fn initial_stop(&mut self) -> Stop {
+        let lines = std::mem::take(&mut self.initial);
+        self.parse_stop(&lines, false)
+    }
+
+    /// `step n` is sent as one command. xsm does not report how many of
+    /// the instructions it executed before the machine ended, so such a step
+    /// is reported with `executed_unknown` for the caller to find out.
+    /// Where the exact instruction matters, xsm is stepped one instruction
+    /// at a time instead: at an IN, INI or HALT, so that the step ends right
+    /// after the IN, before the INI and at the halt, and while watchpoints
+    /// are set, as they make xsm stop anywhere within the step.
+    fn step(&mut self, n: usize) -> Result<Stop, BackendError> {
+        let executing = parse::opcode(&self.next_instr).to_owned();
+        let exact = n == 1 || self.watching || ["IN", "INI", "HALT"].contains(&executing.as_str());
+        if !exact {
+            let response = self.command(format_args!("step {}", n))?;
+            let mut stop = self.parse_stop(&response.lines, response.closed);
+            stop.executed = n;
+            stop.executed_unknown = stop.end.is_some();
+            stop.console_read = stop
+                .status
+                .as_ref()
+                .is_some_and(|status| parse::opcode(parse::previous_instr(&status.text)) == "IN");
+            return Ok(stop);
+        }
+
+        let mut output = Vec::new();
+        let mut executed = 0;
+        loop {
+            let executing = parse::opcode(&self.next_instr).to_owned();
+            let response = self.command(format_args!("step"))?;
+            let mut stop = self.parse_stop(&response.lines, response.closed);
+            executed += 1;
+            output.append(&mut stop.output);
+            let at_ini = parse::opcode(&self.next_instr) == "INI";
+            stop.console_read = executing == "IN";
+            let stopped = stop.end.is_some()
+                || stop.status.is_none()
+                || stop.watch_addr.is_some()
+                || stop.console_read
+                || at_ini;
+            if stopped || executed >= n {
+                stop.output = output;
+                stop.executed = executed;
+                return Ok(stop);
+            }
+        }
+    }
+
+    fn run(&mut self) -> Result<(), BackendError> {
+        self.send(format_args!("continue"))
+    }
+
+    /// xsm stops on SIGINT and prompts again,
+    /// which `poll` picks up like any other stop.
+    fn interrupt(&mut self) -> Result<(), BackendError> {
+        // SAFETY: kill has no memory safety requirements, and the child
+        // has not been waited for, so its pid is still its own.
+        if unsafe { libc::kill(self.xsm.id() as libc::pid_t, libc::SIGINT) } != 0 {
+            return Err(BackendError::Interrupt(io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+
+    /// The machine may run for any length of time, so there is no timeout.
+    fn poll(&mut self) -> Result<Option<Stop>, BackendError> {
+        loop {
+            match self.stdout.try_recv() {
+                Ok(Event::Line(line)) => self.pending.push(line),
+                Ok(Event::Prompt) if self.late > 0 => {
+                    self.late -= 1;
+                    self.pending.clear();
+                }
+                Ok(Event::Prompt) => {
+                    let lines = std::mem::take(&mut self.pending);
+                    return Ok(Some(self.parse_stop(&lines, false)));
+                }
+                Err(TryRecvError::Empty) => return Ok(None),
+                Err(TryRecvError::Disconnected) => {
+                    let lines = std::mem::take(&mut self.pending);
+                    return Ok(Some(self.parse_stop(&lines, true)));
+                }
+            }
+        }
+    }
+
+    fn read_regs(&mut self) -> Result<XSMRegs, BackendError> {
+        let response = self.command(format_args!("reg"))?;
+        self.check_open(&response)?;
+        if response.lines.len() < REG_LINES {
+            return Err(BackendError::Parse(ParseError::Truncated {
+                expected: REG_LINES,
+                found: response.lines.len(),
+            }));
+        }
+        Ok(parse::parse_regs(&response.lines)?)
+    }
+
+    /// xsm has written the page to the `mem` file by the time it prompts again.
+    fn read_mem_page(&mut self, page: usize) -> Result<Vec<XSMWord>, BackendError> {
+        self.quiet_command(format_args!("mem {}", page))?;
+        let mem =
+            fs::read_to_string(self.work_dir.path.join("mem")).map_err(BackendError::MemFile)?;
+        Ok(parse::parse_mem_page(&mem)?)
+    }
+
+    fn watch(&mut self, addr: usize) -> Result<(), BackendError> {
+        self.quiet_command(format_args!("watch {}", addr))?;
+        self.watching = true;
+        Ok(())
+    }
+
+    fn watch_clear(&mut self) -> Result<(), BackendError> {
+        self.quiet_command(format_args!("watchclear"))?;
+        self.watching = false;
+        Ok(())
+    }
+
+    /// The line is read by the IN instruction xsm is executing,
+    /// so there is no prompt after it.
+    fn write_input(&mut self, line: &str) -> Result<(), BackendError> {
+        self.send(format_args!("{}", line))
+    }
+
+    fn restart(&self) -> Result<Box<dyn DebugBackend>, BackendError> {
+        Ok(Box::new(XSMProcess::spawn_with_files(
+            &self.argv,
+            self.timeouts,
+            self.files.clone(),
+        )?))
+    }
+}
+
+impl Drop for XSMProcess {
+    fn drop(&mut self) {
+        let _ = self.xsm.kill();
+        let _ = self.xsm.wait();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn argv(args: &[&str]) -> Vec<String> {
+        args.iter().map(|&arg| arg.to_owned()).collect()
+    }
+
+    #[test]
+    fn only_paths_are_made_absolute() {
+        let cwd = env::current_dir().unwrap();
+        let abs = |path: &str| cwd.join(path).to_string_lossy().into_owned();
+        // Option values stay as they are, whatever files the cwd holds.
+        let resolved = resolve_paths(&argv(&[
+            "xsm",
+            "--timer",
+            "100",
+            "--debug",
+            "disk.xfs",
+            "lib/library.lib",
+            "/tmp/x",
+        ]))
+        .unwrap();
+        assert_eq!(
+            resolved,
+            [
+                "xsm".to_owned(),
+                "--timer".to_owned(),
+                "100".to_owned(),
+                "--debug".to_owned(),
+                abs("disk.xfs"),
+                abs("lib/library.lib"),
+                "/tmp/x".to_owned(),
+            ]
+        );
+        assert_eq!(resolve_paths(&argv(&["./xsm"])).unwrap(), [abs("./xsm")]);
+    }
+}
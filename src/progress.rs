@@ -0,0 +1,51 @@
+//! Progress of long debugger operations, such as going to a far step,
+//! shared between the thread running them and the user interface,
+//! which shows it and may cancel them.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[derive(Debug, Default)]
+pub struct Progress {
+    /// Instructions executed by the current operation
+    done: AtomicUsize,
+    /// Instructions the current operation will execute, or 0 if not known
+    total: AtomicUsize,
+    /// Number of operations started, which numbers them from 1
+    started: AtomicUsize,
+    /// Operations numbered up to this one are cancelled
+    cancelled: AtomicUsize,
+}
+
+impl Progress {
+    /// Starts counting for the next operation.
+    pub fn start(&self, total: Option<usize>) {
+        self.done.store(0, Ordering::Relaxed);
+        self.total.store(total.unwrap_or(0), Ordering::Relaxed);
+        self.started.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn advance(&self, n: usize) {
+        self.done.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Instructions executed so far, and out of how many if known.
+    pub fn get(&self) -> (usize, Option<usize>) {
+        let total = self.total.load(Ordering::Relaxed);
+        (
+            self.done.load(Ordering::Relaxed),
+            Some(total).filter(|&total| total > 0),
+        )
+    }
+
+    /// Cancels the operations numbered up to `op`, including the ones
+    /// that have not started yet. They stop at the next chunk of instructions.
+    pub fn cancel(&self, op: usize) {
+        self.cancelled.fetch_max(op, Ordering::SeqCst);
+    }
+
+    /// True if the current operation is cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        let started = self.started.load(Ordering::SeqCst);
+        started > 0 && started <= self.cancelled.load(Ordering::SeqCst)
+    }
+}
@@ -0,0 +1,231 @@
+//! Replays a trace recorded by `TraceWriter` in place of a live xsm.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::backend::{BackendError, DebugBackend, End, Status, Stop};
+use crate::trace::TraceRecord;
+use crate::word::XSMWord;
+use crate::xsm::{Mode, XSMRegs};
+
+pub struct TraceReplay {
+    path: PathBuf,
+    records: Vec<TraceRecord>,
+    /// Index of the next record to execute
+    pos: usize,
+    regs: XSMRegs,
+    /// Instructions seen anywhere in the trace, by (is user mode, IP)
+    code: HashMap<(bool, usize), String>,
+    running: bool,
+}
+
+impl TraceReplay {
+    pub fn open(path: &Path) -> Result<Self, BackendError> {
+        Self::read(path).map_err(|source| BackendError::Trace {
+            path: path.to_owned(),
+            source,
+        })
+    }
+
+    fn read(path: &Path) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        let mut records = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let record: TraceRecord = serde_json::from_str(line).map_err(|e| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {}", i + 1, e))
+            })?;
+            records.push(record);
+        }
+        let code = records
+            .iter()
+            .map(|record| ((record.mode == "user", record.ip), record.instr.clone()))
+            .collect();
+        Ok(Self {
+            path: path.to_owned(),
+            records,
+            pos: 0,
+            regs: XSMRegs::default(),
+            code,
+            running: false,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    pub fn pos(&self) -> usize {
+        self.pos
+    }
+
+    /// The record of the instruction to be executed next,
+    /// or None at the end of the trace.
+    pub fn next_record(&self) -> Option<&TraceRecord> {
+        self.records.get(self.pos)
+    }
+
+    /// Executes the next recorded instruction.
+    /// Returns its record, or None at the end of the trace.
+    pub fn advance(&mut self) -> Option<&TraceRecord> {
+        let record = self.records.get(self.pos)?;
+        for (name, value) in &record.regs {
+            if let Some(reg) = self.regs.get_mut(name) {
+                *reg = XSMWord::parse(value);
+            }
+        }
+        self.pos += 1;
+        Some(record)
+    }
+
+    /// Registers after the instructions executed so far.
+    pub fn regs(&self) -> &XSMRegs {
+        &self.regs
+    }
+
+    /// The stop after the records executed so far.
+    /// The end of the trace is treated as a halt.
+    fn stop(&self, output: Vec<String>, input: Vec<(usize, String)>, executed: usize) -> Stop {
+        let status = self.next_record().map(|record| Status {
+            text: format!(
+                "Trace step {} of {}\nMode: {}\nNext instruction at IP = {}: {}\n",
+                self.pos,
+                self.len(),
+                record.mode.to_uppercase(),
+                record.ip,
+                record.instr
+            ),
+            mode: if record.mode == "user" {
+                Mode::User
+            } else {
+                Mode::Kernel
+            },
+            ip: record.ip,
+            next_instr: record.instr.clone(),
+        });
+        Stop {
+            output,
+            watch_addr: None,
+            end: if status.is_none() {
+                Some(End::Halted)
+            } else {
+                None
+            },
+            status,
+            executed,
+            executed_unknown: false,
+            console_read: false,
+            input,
+            error: None,
+        }
+    }
+}
+
+impl DebugBackend for TraceReplay {
+    fn initial_stop(&mut self) -> Stop {
+        self.stop(Vec::new(), Vec::new(), 0)
+    }
+
+    fn step(&mut self, n: usize) -> Result<Stop, BackendError> {
+        let mut output = Vec::new();
+        let mut input = Vec::new();
+        let mut executed = 0;
+        while executed < n {
+            match self.advance() {
+                Some(record) => {
+                    output.extend(record.output.iter().cloned());
+                    input.extend(record.input.iter().map(|line| (record.step, line.clone())));
+                }
+                None => break,
+            }
+            executed += 1;
+        }
+        Ok(self.stop(output, input, executed))
+    }
+
+    fn run(&mut self) -> Result<(), BackendError> {
+        self.running = true;
+        Ok(())
+    }
+
+    /// `poll` runs to the end in one go, so there is never anything to stop.
+    fn interrupt(&mut self) -> Result<(), BackendError> {
+        Ok(())
+    }
+
+    /// Runs to the end of the trace, or to just after a BRKP instruction
+    /// as xsm would.
+    fn poll(&mut self) -> Result<Option<Stop>, BackendError> {
+        if !self.running {
+            return Ok(None);
+        }
+        self.running = false;
+        let mut output = Vec::new();
+        let mut input = Vec::new();
+        let mut executed = 0;
+        while let Some(record) = self.advance() {
+            output.extend(record.output.iter().cloned());
+            input.extend(record.input.iter().map(|line| (record.step, line.clone())));
+            executed += 1;
+            if record.instr.starts_with("BRKP") {
+                break;
+            }
+        }
+        Ok(Some(self.stop(output, input, executed)))
+    }
+
+    /// IP is taken from the next record, since IP is not
+    /// among the registers changed by the previous one after a jump.
+    fn read_regs(&mut self) -> Result<XSMRegs, BackendError> {
+        let mut regs = self.regs.clone();
+        if let Some(record) = self.next_record() {
+            regs.ip = XSMWord::Int(record.ip as i64);
+        }
+        Ok(regs)
+    }
+
+    fn read_mem_page(&mut self, _page: usize) -> Result<Vec<XSMWord>, BackendError> {
+        Ok(Vec::new())
+    }
+
+    fn watch(&mut self, _addr: usize) -> Result<(), BackendError> {
+        Ok(())
+    }
+
+    fn watch_clear(&mut self) -> Result<(), BackendError> {
+        Ok(())
+    }
+
+    /// Console input was already consumed when the trace was recorded.
+    fn write_input(&mut self, _line: &str) -> Result<(), BackendError> {
+        Ok(())
+    }
+
+    fn restart(&self) -> Result<Box<dyn DebugBackend>, BackendError> {
+        Ok(Box::new(TraceReplay::open(&self.path)?))
+    }
+
+    fn has_memory(&self) -> bool {
+        false
+    }
+
+    fn instr_at(&self, is_user: bool, ip: usize) -> Option<&str> {
+        self.code.get(&(is_user, ip)).map(String::as_str)
+    }
+
+    fn replay_path(&self) -> Option<&Path> {
+        Some(&self.path)
+    }
+}
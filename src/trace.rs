@@ -0,0 +1,88 @@
+//! Execution traces in JSON Lines format: one record per executed instruction.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::xsm::XSMRegs;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TraceRecord {
+    /// Number of instructions executed before this one
+    pub step: usize,
+    /// "kernel" or "user"
+    pub mode: String,
+    pub ip: usize,
+    /// The instruction as shown in the status lines of xsm
+    pub instr: String,
+    /// Registers changed by the instruction, with their new values.
+    /// The first record of a trace holds all registers.
+    pub regs: BTreeMap<String, String>,
+    /// Lines printed by OUT
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub output: Vec<String>,
+    /// Console input written to the machine before this instruction
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub input: Vec<String>,
+}
+
+pub struct TraceWriter {
+    out: BufWriter<File>,
+    last_regs: XSMRegs,
+    /// Console input for the next record
+    input: Vec<String>,
+}
+
+impl TraceWriter {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            out: BufWriter::new(File::create(path)?),
+            last_regs: XSMRegs::default(),
+            input: Vec::new(),
+        })
+    }
+
+    /// Writes a record for the instruction that was at `ip`,
+    /// given the registers after executing it.
+    pub fn record(
+        &mut self,
+        step: usize,
+        is_user: bool,
+        ip: usize,
+        instr: &str,
+        regs: &XSMRegs,
+        output: &[String],
+    ) -> io::Result<()> {
+        let changed = regs
+            .named()
+            .zip(self.last_regs.named())
+            .filter(|((_, new), (_, old))| new != old)
+            .map(|((name, new), _)| (name, new.to_string()))
+            .collect();
+        let record = TraceRecord {
+            step,
+            mode: if is_user { "user" } else { "kernel" }.to_owned(),
+            ip,
+            instr: instr.to_owned(),
+            regs: changed,
+            output: output.to_vec(),
+            input: std::mem::take(&mut self.input),
+        };
+        serde_json::to_writer(&mut self.out, &record)?;
+        writeln!(self.out)?;
+        self.last_regs = regs.clone();
+        Ok(())
+    }
+
+    /// Adds a line of console input to the next record.
+    pub fn input(&mut self, line: &str) {
+        self.input.push(line.to_owned());
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
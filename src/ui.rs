@@ -4,31 +4,60 @@ use std::convert::TryInto;
 
 use imgui::{Condition, Ui};
 
-use crate::xsm::XSM;
+use xdb::backend::End;
+use xdb::process;
+use xdb::worker::{Command, MemView, Worker};
+use xdb::xsm::XSM;
 
 pub struct UI {
-    xsm: XSM,
+    worker: Worker,
     is_continue: bool,
-    step: usize,
     update_delay: f64,
     step_size: usize,
     last_time: f64,
+    /// For the debugger command box, which is commented out
+    #[allow(dead_code)]
     input_cmd: imgui::ImString,
     ff_till: usize,
+    bp_addr: i32,
+    bp_is_virtual: bool,
+    bp_condition: imgui::ImString,
+    bp_ignore_count: i32,
+    wp_addr: i32,
+    trace_file: imgui::ImString,
+    console_input: imgui::ImString,
+    queue_input: bool,
+    restart_cmd: imgui::ImString,
+    repeat_input: bool,
     data: HashMap<&'static str, Box<dyn Any>>,
 }
 
 impl UI {
     pub fn new(xsm: XSM) -> Self {
+        let mut restart_cmd = imgui::ImString::with_capacity(1024);
+        restart_cmd.push_str(xsm.get_command());
         Self {
-            xsm,
+            worker: Worker::spawn(xsm),
             is_continue: true,
-            step: 0,
             step_size: 1,
             last_time: 0.0,
             update_delay: 1.0,
             input_cmd: imgui::ImString::new(""),
             ff_till: 0,
+            bp_addr: 0,
+            bp_is_virtual: false,
+            bp_condition: imgui::ImString::with_capacity(256),
+            bp_ignore_count: 0,
+            wp_addr: 0,
+            trace_file: {
+                let mut trace_file = imgui::ImString::with_capacity(256);
+                trace_file.push_str("trace.jsonl");
+                trace_file
+            },
+            console_input: imgui::ImString::with_capacity(256),
+            queue_input: false,
+            restart_cmd,
+            repeat_input: true,
             data: Default::default(),
         }
     }
@@ -37,16 +66,33 @@ impl UI {
         imgui::Window::new(im_str!("Code"))
             .size([300.0, 100.0], Condition::FirstUseEver)
             .build(ui, || {
-                let lines = 20;
-                let (base, ip, code_lines) = self.xsm.get_code(lines);
+                let snapshot = self.worker.snapshot();
+                let (base, ip, code_lines) = &snapshot.code;
+                let (base, ip) = (*base, *ip);
+                let is_virtual = snapshot.is_user_mode;
+                let mut toggle = None;
                 for (i, code) in code_lines.iter().enumerate() {
                     let instr_addr = base + 2 * i;
-                    if instr_addr == ip {
-                        imgui::MenuItem::new(&im_str!("[{}]: {}", instr_addr, code)).build(ui);
+                    let has_bp = snapshot
+                        .breakpoints
+                        .iter()
+                        .any(|bp| bp.addr == instr_addr && bp.is_virtual == is_virtual);
+                    let bp_mark = if has_bp { "*" } else { " " };
+                    let clicked = if instr_addr == ip {
+                        imgui::MenuItem::new(&im_str!("{}[{}]: {}", bp_mark, instr_addr, code))
+                            .build(ui)
                     } else {
-                        imgui::MenuItem::new(&im_str!(" {} : {}", instr_addr, code)).build(ui);
+                        imgui::MenuItem::new(&im_str!("{} {} : {}", bp_mark, instr_addr, code))
+                            .build(ui)
+                    };
+                    if clicked {
+                        toggle = Some(instr_addr);
                     }
                 }
+                if let Some(addr) = toggle {
+                    self.worker
+                        .send(Command::ToggleBreakpoint { addr, is_virtual });
+                }
             });
     }
 
@@ -54,12 +100,13 @@ impl UI {
         imgui::Window::new(im_str!("Registers"))
             .size([300.0, 100.0], Condition::FirstUseEver)
             .build(ui, || {
+                let regs = &self.worker.snapshot().regs;
                 for i in 0..=15usize {
-                    ui.text(format!("R{}: {}", i, &self.xsm.get_regs().r[i]));
+                    ui.text(format!("R{}: {}", i, &regs.r[i]));
                 }
                 ui.separator();
                 for i in 15..20usize {
-                    ui.text(format!("R{}: {}", i, &self.xsm.get_regs().r[i]));
+                    ui.text(format!("R{}: {}", i, &regs.r[i]));
                 }
             });
     }
@@ -68,33 +115,34 @@ impl UI {
         imgui::Window::new(im_str!("Registers Extra"))
             .size([300.0, 100.0], Condition::FirstUseEver)
             .build(ui, || {
+                let regs = &self.worker.snapshot().regs;
                 ui.text("PORTS");
                 ui.separator();
 
                 for i in 0..4usize {
-                    ui.text(format!("P{}: {}", i, &self.xsm.get_regs().p[i]));
+                    ui.text(format!("P{}: {}", i, &regs.p[i]));
                 }
 
                 ui.new_line();
                 ui.text("STACK");
                 ui.separator();
-                ui.text(format!("BP: {}", &self.xsm.get_regs().bp));
-                ui.text(format!("SP: {}", &self.xsm.get_regs().sp));
+                ui.text(format!("BP: {}", &regs.bp));
+                ui.text(format!("SP: {}", &regs.sp));
 
                 ui.new_line();
                 ui.text("PAGE TABLE");
                 ui.separator();
-                ui.text(format!("PTBR: {}", &self.xsm.get_regs().ptbr));
-                ui.text(format!("PTLR: {}", &self.xsm.get_regs().ptlr));
+                ui.text(format!("PTBR: {}", &regs.ptbr));
+                ui.text(format!("PTLR: {}", &regs.ptlr));
 
                 ui.new_line();
                 ui.text("OTHERS");
                 ui.separator();
-                ui.text(format!("IP: {}", &self.xsm.get_regs().ip));
-                ui.text(format!("EIP: {}", &self.xsm.get_regs().eip));
-                ui.text(format!("EC: {}", &self.xsm.get_regs().ec));
-                ui.text(format!("EPN: {}", &self.xsm.get_regs().epn));
-                ui.text(format!("EMA: {}", &self.xsm.get_regs().epn));
+                ui.text(format!("IP: {}", &regs.ip));
+                ui.text(format!("EIP: {}", &regs.eip));
+                ui.text(format!("EC: {}", &regs.ec));
+                ui.text(format!("EPN: {}", &regs.epn));
+                ui.text(format!("EMA: {}", &regs.epn));
             });
     }
 
@@ -102,7 +150,7 @@ impl UI {
         imgui::Window::new(im_str!("Page Table"))
             .size([300.0, 100.0], Condition::FirstUseEver)
             .build(ui, || {
-                for (i, entry) in self.xsm.get_page_table().iter().enumerate() {
+                for (i, entry) in self.worker.snapshot().page_table.iter().enumerate() {
                     ui.text(format!("{} -> {}    [{}]", i, entry.phy, entry.aux))
                 }
             });
@@ -112,8 +160,25 @@ impl UI {
         imgui::Window::new(im_str!("Errors"))
             .size([300.0, 100.0], Condition::FirstUseEver)
             .build(ui, || {
-                for error in self.xsm.get_errors() {
-                    ui.text_wrapped(&im_str!("{:#?}", error))
+                if self.worker.snapshot().errors.is_empty() {
+                    ui.text("No errors");
+                    return;
+                }
+                if ui.button(im_str!("Dismiss All##err"), [0.0, 0.0]) {
+                    self.worker.send(Command::ClearErrors);
+                    return;
+                }
+                ui.separator();
+                let mut dismiss = None;
+                for (i, error) in self.worker.snapshot().errors.iter().enumerate() {
+                    if ui.button(&im_str!("Dismiss##err{}", i), [0.0, 0.0]) {
+                        dismiss = Some(i);
+                    }
+                    ui.same_line(0.0);
+                    ui.text_wrapped(&im_str!("{}", error));
+                }
+                if let Some(i) = dismiss {
+                    self.worker.send(Command::DismissError(i));
                 }
             });
     }
@@ -122,8 +187,88 @@ impl UI {
         imgui::Window::new(im_str!("Output"))
             .size([300.0, 100.0], Condition::FirstUseEver)
             .build(ui, || {
-                for line in self.xsm.get_output() {
-                    ui.text(&line);
+                for line in &self.worker.snapshot().output {
+                    ui.text(line);
+                }
+            });
+    }
+
+    fn render_console(&mut self, ui: &mut Ui) {
+        imgui::Window::new(im_str!("Console"))
+            .size([300.0, 150.0], Condition::FirstUseEver)
+            .build(ui, || {
+                let snapshot = self.worker.snapshot();
+                let waiting = snapshot.waiting_input;
+                if snapshot.replay_path.is_some() {
+                    ui.text_wrapped(im_str!("Console input was recorded in the trace."));
+                } else if waiting {
+                    ui.text_wrapped(im_str!("WAITING FOR CONSOLE INPUT"));
+                } else if snapshot.running {
+                    ui.text_wrapped(im_str!("Input is read by the running machine."));
+                } else {
+                    ui.text_wrapped(im_str!("The machine is not reading input."));
+                }
+                let can_send = waiting || snapshot.running || self.queue_input;
+                for (step, line) in &snapshot.inputs {
+                    ui.text(format!("{:>8}: {}", step, line));
+                }
+                for line in &snapshot.queued_input {
+                    ui.text(format!("  queued: {}", line));
+                }
+                let has_queue = !snapshot.queued_input.is_empty();
+
+                ui.separator();
+                let mut send = ui
+                    .input_text(im_str!("##console"), &mut self.console_input)
+                    .enter_returns_true(true)
+                    .build();
+                ui.same_line(0.0);
+                send = ui.button(im_str!("Send##console"), [0.0, 0.0]) || send;
+                if send && can_send {
+                    let line = self.console_input.to_str().to_owned();
+                    self.worker.send(if self.queue_input {
+                        Command::QueueInput(line)
+                    } else {
+                        Command::WriteInput(line)
+                    });
+                    self.console_input.clear();
+                }
+                ui.checkbox(im_str!("Queue for later input"), &mut self.queue_input);
+                if has_queue {
+                    ui.same_line(0.0);
+                    if ui.button(im_str!("Clear Queue"), [0.0, 0.0]) {
+                        self.worker.send(Command::ClearQueuedInput);
+                    }
+                }
+            });
+    }
+
+    fn render_session(&mut self, ui: &mut Ui) {
+        imgui::Window::new(im_str!("Session"))
+            .size([300.0, 100.0], Condition::FirstUseEver)
+            .build(ui, || {
+                ui.text(im_str!("Command line"));
+                ui.input_text(im_str!("##restart_cmd"), &mut self.restart_cmd)
+                    .build();
+                ui.checkbox(im_str!("Same console input"), &mut self.repeat_input);
+                if ui.button(im_str!("Restart"), [0.0, 0.0]) {
+                    let line = self.restart_cmd.to_str().trim();
+                    // An unchanged command line restarts whatever runs the machine,
+                    // be it xsm, the emulator or a trace.
+                    let argv = if line == self.worker.snapshot().command.trim() {
+                        None
+                    } else {
+                        Some(process::split_command_line(line))
+                    };
+                    self.worker.send(Command::Restart {
+                        argv,
+                        repeat_input: self.repeat_input,
+                    });
+                }
+                ui.same_line(0.0);
+                if ui.button(im_str!("Reset##restart_cmd"), [0.0, 0.0]) {
+                    self.restart_cmd.clear();
+                    self.restart_cmd.push_str(&self.worker.snapshot().command);
                 }
             });
     }
@@ -132,7 +277,7 @@ impl UI {
         imgui::Window::new(im_str!("Status"))
             .size([300.0, 100.0], Condition::FirstUseEver)
             .build(ui, || {
-                for line in self.xsm.get_status().lines() {
+                for line in self.worker.snapshot().status.lines() {
                     ui.text_wrapped(&im_str!("{}", line));
                 }
                 ui.separator();
@@ -154,6 +299,160 @@ impl UI {
             });
     }
 
+    fn render_breakpoints(&mut self, ui: &mut Ui) {
+        imgui::Window::new(im_str!("Breakpoints"))
+            .size([300.0, 100.0], Condition::FirstUseEver)
+            .build(ui, || {
+                ui.push_item_width(100.0);
+                let mut add_pressed = ui
+                    .input_int(im_str!("Address##bp"), &mut self.bp_addr)
+                    .enter_returns_true(true)
+                    .build();
+                ui.checkbox(im_str!("Virtual Address##bp"), &mut self.bp_is_virtual);
+                ui.push_item_width(200.0);
+                ui.input_text(im_str!("Condition##bp"), &mut self.bp_condition)
+                    .build();
+                ui.push_item_width(100.0);
+                ui.input_int(im_str!("Ignore Count##bp"), &mut self.bp_ignore_count)
+                    .build();
+                add_pressed = add_pressed || ui.button(im_str!("Add / Update"), [0.0, 0.0]);
+                if add_pressed && self.bp_addr >= 0 {
+                    self.worker.send(Command::SetBreakpoint {
+                        addr: self.bp_addr as usize,
+                        is_virtual: self.bp_is_virtual,
+                        condition: self.bp_condition.to_str().to_owned(),
+                        ignore_count: self.bp_ignore_count.max(0) as usize,
+                    });
+                }
+                if let Some(error) = &self.worker.snapshot().bp_error {
+                    ui.text_wrapped(&im_str!("{}", error));
+                }
+                ui.text_wrapped(im_str!(
+                    "Click a line in the Code window to toggle a breakpoint."
+                ));
+                ui.text_wrapped(im_str!(
+                    "Conditions use registers, mode, [phy addr] and v[vir addr], \
+                     e.g. R1 == 5 && [PTBR + 4] == -1"
+                ));
+
+                ui.separator();
+                let mut remove = None;
+                let mut set_enabled = None;
+                for (i, bp) in self.worker.snapshot().breakpoints.iter().enumerate() {
+                    let mut enabled = bp.enabled;
+                    let kind = if bp.is_virtual { "vir" } else { "phy" };
+                    if ui.checkbox(&im_str!("{} {}##bp{}", kind, bp.addr, i), &mut enabled) {
+                        set_enabled = Some((i, enabled));
+                    }
+                    ui.same_line(150.0);
+                    ui.text(format!("hits: {}", bp.hits));
+                    ui.same_line(230.0);
+                    if ui.button(&im_str!("Delete##bp{}", i), [0.0, 0.0]) {
+                        remove = Some(i);
+                    }
+                    if let Some(condition) = &bp.condition {
+                        ui.text_wrapped(&im_str!("    if {}", condition.source()));
+                    }
+                    if bp.ignore_count > 0 {
+                        ui.text(format!("    ignore next {} hits", bp.ignore_count));
+                    }
+                }
+                if let Some((i, enabled)) = set_enabled {
+                    self.worker.send(Command::SetBreakpointEnabled(i, enabled));
+                }
+                if let Some(i) = remove {
+                    self.worker.send(Command::RemoveBreakpoint(i));
+                }
+            });
+    }
+
+    fn render_watchpoints(&mut self, ui: &mut Ui) {
+        imgui::Window::new(im_str!("Watchpoints"))
+            .size([300.0, 100.0], Condition::FirstUseEver)
+            .build(ui, || {
+                ui.push_item_width(100.0);
+                let mut add_pressed = ui
+                    .input_int(im_str!("Physical Address##wp"), &mut self.wp_addr)
+                    .enter_returns_true(true)
+                    .build();
+                add_pressed = add_pressed || ui.button(im_str!("Add##wp"), [0.0, 0.0]);
+                if add_pressed && self.wp_addr >= 0 {
+                    self.worker
+                        .send(Command::AddWatchpoint(self.wp_addr as usize));
+                }
+                ui.same_line(0.0);
+                if ui.button(im_str!("Clear All##wp"), [0.0, 0.0]) {
+                    self.worker.send(Command::ClearWatchpoints);
+                }
+
+                ui.separator();
+                let mut remove = None;
+                for (i, wp) in self.worker.snapshot().watchpoints.iter().enumerate() {
+                    ui.text(format!("{}: {}", wp.addr, wp.value));
+                    ui.same_line(150.0);
+                    ui.text(format!("hits: {}", wp.hits));
+                    ui.same_line(230.0);
+                    if ui.button(&im_str!("Delete##wp{}", i), [0.0, 0.0]) {
+                        remove = Some(i);
+                    }
+                    if wp.hits > 0 {
+                        ui.text(format!("    was: {}", wp.old_value));
+                    }
+                }
+                if let Some(i) = remove {
+                    self.worker.send(Command::RemoveWatchpoint(i));
+                }
+            });
+    }
+
+    fn render_history(&mut self, ui: &mut Ui) {
+        imgui::Window::new(im_str!("History"))
+            .size([300.0, 100.0], Condition::FirstUseEver)
+            .build(ui, || {
+                let snapshot = self.worker.snapshot();
+                let len = snapshot.history_len;
+                let viewing_history = snapshot.viewing_history;
+                let mut pos = snapshot.history_pos as i32;
+                let mut capacity = snapshot.history_capacity as i32;
+                if viewing_history {
+                    ui.text_wrapped(&im_str!("Viewing step {} (read-only)", snapshot.step_count));
+                } else {
+                    ui.text_wrapped(im_str!("Viewing the live machine"));
+                }
+                if ui.button(im_str!("Step Back"), [0.0, 0.0]) {
+                    self.is_continue = false;
+                    self.worker.send(Command::StepBack);
+                }
+                ui.same_line(0.0);
+                if ui.button(im_str!("Step Forward"), [0.0, 0.0]) {
+                    self.is_continue = false;
+                    if viewing_history {
+                        self.worker.send(Command::Step(1));
+                    }
+                }
+                ui.same_line(0.0);
+                if ui.button(im_str!("Live"), [0.0, 0.0]) {
+                    self.worker.send(Command::GoLive);
+                }
+
+                ui.push_item_width(-1.0);
+                if imgui::Slider::new(im_str!("##history"), 0..=len as i32).build(ui, &mut pos) {
+                    self.is_continue = false;
+                    self.worker.send(Command::SetHistoryPos(pos as usize));
+                }
+
+                ui.push_item_width(100.0);
+                if ui
+                    .input_int(im_str!("States kept"), &mut capacity)
+                    .enter_returns_true(true)
+                    .build()
+                {
+                    self.worker
+                        .send(Command::SetHistoryCapacity(capacity.max(0) as usize));
+                }
+            });
+    }
+
     fn render_control_panel(&mut self, ui: &mut Ui) {
         imgui::Window::new(im_str!("Control Panel"))
             .size([300.0, 100.0], Condition::FirstUseEver)
@@ -179,30 +478,147 @@ impl UI {
                     if ui.button(im_str!("Pause"), [0.0, 0.0]) {
                         self.is_continue = false;
                     }
+                } else if ui.button(im_str!("Resume"), [0.0, 0.0]) {
+                    self.is_continue = true;
+                }
+
+                ui.separator();
+                if self.worker.is_busy() {
+                    let (done, total) = self.worker.get_progress();
+                    match total {
+                        Some(total) => {
+                            let fraction = done as f32 / total.max(1) as f32;
+                            imgui::ProgressBar::new(fraction.min(1.0))
+                                .overlay_text(&im_str!("{} / {} instructions", done, total))
+                                .build(ui);
+                        }
+                        None => ui.text(im_str!("Working... {} instructions", done)),
+                    }
+                    if ui.button(im_str!("Cancel"), [0.0, 0.0]) {
+                        self.worker.cancel();
+                    }
+                } else if self.worker.snapshot().running {
+                    ui.text_wrapped(im_str!("Running... (stops at BRKP, a watchpoint or HALT)"));
+                    if ui.button(im_str!("Stop"), [0.0, 0.0]) {
+                        self.is_continue = false;
+                        self.worker.send(Command::Stop);
+                    }
                 } else {
-                    if ui.button(im_str!("Resume"), [0.0, 0.0]) {
-                        self.is_continue = true;
+                    if ui.button(im_str!("Step"), [0.0, 0.0]) {
+                        self.is_continue = false;
+                        self.worker.send(Command::Step(1));
+                    }
+                    ui.same_line(0.0);
+                    if ui.button(im_str!("Step Over"), [0.0, 0.0]) {
+                        self.is_continue = false;
+                        self.worker.send(Command::StepOver);
+                    }
+                    ui.same_line(0.0);
+                    if ui.button(im_str!("Step Out"), [0.0, 0.0]) {
+                        self.is_continue = false;
+                        self.worker.send(Command::StepOut);
+                    }
+                    ui.same_line(0.0);
+                    if ui.button(im_str!("Run"), [0.0, 0.0]) {
+                        self.is_continue = false;
+                        self.worker.send(Command::Run);
+                    }
+                }
+
+                ui.separator();
+                ui.push_item_width(200.0);
+                let snapshot = self.worker.snapshot();
+                if let Some(path) = &snapshot.replay_path {
+                    ui.text_wrapped(&im_str!("Replaying trace {}", path.display()));
+                } else if snapshot.tracing {
+                    ui.text(im_str!("Recording trace to {}", self.trace_file));
+                    if ui.button(im_str!("Stop Trace"), [0.0, 0.0]) {
+                        self.worker.send(Command::StopTrace);
+                    }
+                } else {
+                    if let Some(error) = &snapshot.trace_error {
+                        ui.text_wrapped(&im_str!("Failed to create trace file: {}", error));
+                    }
+                    ui.input_text(im_str!("##trace_file"), &mut self.trace_file)
+                        .build();
+                    ui.same_line(0.0);
+                    if ui.button(im_str!("Start Trace"), [0.0, 0.0]) {
+                        let path = self.trace_file.to_str().into();
+                        self.worker.send(Command::StartTrace(path));
                     }
                 }
+                ui.push_item_width(100.0);
 
                 ui.separator();
-                ui.text(im_str!("Current step: {}", self.step));
+                let snapshot = self.worker.snapshot();
+                let step = snapshot.step_count;
+                if snapshot.steps_exact {
+                    ui.text(im_str!("Current step: {}", step));
+                } else {
+                    ui.text(im_str!("Current step: unknown (ran with continue)"));
+                }
                 let mut ff_till = self.ff_till as i32;
                 let mut ff_till_pressed = ui
                     .input_int(im_str!(""), &mut ff_till)
                     .enter_returns_true(true)
                     .build();
                 self.ff_till = ff_till as usize;
-                ff_till_pressed =
-                    ff_till_pressed || ui.button(im_str!("Fast forward till step"), [0.0, 0.0]);
-                if ff_till_pressed && ff_till > self.step as i32 {
-                    let step = ff_till as usize - self.step;
-                    self.xsm.step(step);
-                    self.step += step;
+                ff_till_pressed = ff_till_pressed || ui.button(im_str!("Go to step"), [0.0, 0.0]);
+                if ff_till_pressed && ff_till >= 0 && !self.worker.is_busy() {
+                    self.is_continue = false;
+                    self.worker.send(Command::GotoStep(ff_till as usize));
                     self.last_time = ui.time();
                 }
+                ui.text_wrapped(im_str!(
+                    "Going back beyond the history restarts xsm and replays to the step."
+                ));
+
+                let snapshot = self.worker.snapshot();
+                if let Some(bp) = &snapshot.breakpoint_hit {
+                    let kind = if bp.is_virtual { "virtual" } else { "physical" };
+                    let addr = bp.addr;
+                    self.is_continue = false;
+                    ui.separator();
+                    ui.text_wrapped(&im_str!("BREAKPOINT HIT at {} address {}", kind, addr));
+                    ui.text_wrapped(im_str!("Machine is auto-paused by the debugger."));
+                }
 
-                if self.xsm.is_next_halt() {
+                if snapshot.watch_triggered {
+                    self.is_continue = false;
+                    ui.separator();
+                    ui.text_wrapped(im_str!("WATCHPOINT TRIGGERED"));
+                    for wp in &snapshot.watchpoint_hits {
+                        ui.text_wrapped(&im_str!("{}: {} -> {}", wp.addr, wp.old_value, wp.value));
+                    }
+                    ui.text_wrapped(im_str!("Machine is auto-paused by the debugger."));
+                }
+
+                if snapshot.waiting_input {
+                    ui.separator();
+                    ui.text_wrapped(im_str!("WAITING FOR CONSOLE INPUT"));
+                    ui.text_wrapped(im_str!("Type a line in the Console window."));
+                }
+
+                if let Some(end) = snapshot.end {
+                    self.is_continue = false;
+                    ui.separator();
+                    match end {
+                        End::Halted => ui.text_wrapped(im_str!("MACHINE HALTED")),
+                        End::Crashed(status) => {
+                            ui.text_wrapped(im_str!("XSM CRASHED"));
+                            ui.text_wrapped(&im_str!("xsm exited without halting: {}", status));
+                        }
+                        End::Killed(status) => {
+                            ui.text_wrapped(im_str!("XSM KILLED"));
+                            ui.text_wrapped(&im_str!("xsm was stopped by {}", status));
+                        }
+                    }
+                    ui.text_wrapped(im_str!(
+                        "Use other windows to inspect the last state of the machine."
+                    ));
+                }
+
+                if snapshot.is_next_halt {
                     self.is_continue = false;
                     ui.separator();
                     ui.text_wrapped(im_str!("Next instruction is HALT"));
@@ -212,7 +628,7 @@ impl UI {
                     ));
                 }
 
-                if self.xsm.is_exception_edge() {
+                if snapshot.is_exception_edge {
                     self.is_continue = false;
                     ui.separator();
                     ui.text_wrapped(im_str!("EXCEPTION DETECTED"));
@@ -229,11 +645,10 @@ impl UI {
             mem_addr: i32,
             is_virtual: bool,
             len: i32,
-            data: Vec<String>,
-            data_base_addr: usize,
-            fetch: bool,
             live: bool,
-        };
+            /// The view last sent to the worker
+            shown: Option<MemView>,
+        }
         if !self.data.contains_key(title) {
             self.data.insert(
                 title,
@@ -241,10 +656,8 @@ impl UI {
                     mem_addr: 0,
                     is_virtual: false,
                     len: 0,
-                    data: Vec::new(),
-                    data_base_addr: 0,
-                    fetch: false,
                     live: false,
+                    shown: None,
                 }),
             );
         }
@@ -252,59 +665,60 @@ impl UI {
         imgui::Window::new(&im_str!("{}", title))
             .size([300.0, 100.0], Condition::FirstUseEver)
             .build(ui, || {
-                let data: &MemStruct = self.data.get(title).unwrap().downcast_ref().unwrap();
-                let data_new = if data.fetch || data.live {
-                    let start_addr: usize = (data.mem_addr.max(0)) as _;
-                    let end_addr: usize = (data.mem_addr.max(0) + data.len.max(0)) as _;
-                    if data.is_virtual {
-                        Some((
-                            start_addr,
-                            self.xsm.read_mem_range_vir(start_addr, end_addr),
-                        ))
-                    } else {
-                        Some((start_addr, self.xsm.read_mem_range(start_addr, end_addr)))
-                    }
-                } else {
-                    None
-                };
                 let data: &mut MemStruct =
                     self.data.get_mut(title).unwrap().downcast_mut().unwrap();
-                if let Some((start_addr, data_new)) = data_new {
-                    data.fetch = false;
-                    data.data_base_addr = start_addr;
-                    data.data = data_new;
-                }
-
                 ui.input_int(im_str!("Memory Address"), &mut data.mem_addr)
                     .build();
                 ui.checkbox(im_str!("Virtual Address"), &mut data.is_virtual);
                 ui.input_int(im_str!("Length"), &mut data.len).build();
-                if ui.button(im_str!("Fetch"), [0.0, 0.0]) {
-                    data.fetch = true;
-                }
+                let fetch = ui.button(im_str!("Fetch"), [0.0, 0.0]);
                 ui.same_line(0.0);
                 ui.checkbox(im_str!("Live"), &mut data.live);
-                for (i, line) in data.data.iter().enumerate() {
-                    ui.text(im_str!("{}: {}", i as i32 + data.mem_addr, line));
+
+                // A live window follows its fields as they are edited.
+                let view = MemView {
+                    addr: data.mem_addr.max(0) as usize,
+                    len: data.len.max(0) as usize,
+                    is_virtual: data.is_virtual,
+                    live: data.live,
+                };
+                let changed = data.shown.as_ref() != Some(&view);
+                let was_live = data.shown.as_ref().is_some_and(|shown| shown.live);
+                if fetch || (changed && (view.live || was_live)) {
+                    data.shown = Some(view.clone());
+                    self.worker.send(Command::ShowMem(title, view));
+                }
+
+                if let Some((base, words)) = self.worker.snapshot().mem.get(title) {
+                    for (i, word) in words.iter().enumerate() {
+                        ui.text(im_str!("{}: {}", base + i, word));
+                    }
                 }
             });
     }
 
     pub fn render_all(&mut self, ui: &mut Ui) {
+        self.worker.update();
         self.render_code(ui);
         self.render_regs1(ui);
         self.render_regs2(ui);
         self.render_page_table(ui);
         self.render_errors(ui);
         self.render_output(ui);
+        self.render_console(ui);
         self.render_status(ui);
         self.render_control_panel(ui);
+        self.render_session(ui);
+        self.render_breakpoints(ui);
+        self.render_watchpoints(ui);
+        self.render_history(ui);
         self.render_mem_window(ui, "Memory Window 1");
         self.render_mem_window(ui, "Memory Window 2");
 
-        if self.is_continue && ui.time() - self.last_time > self.update_delay {
-            self.xsm.step(self.step_size);
-            self.step += self.step_size;
+        // The worker polls the machine while it runs.
+        let idle = !self.worker.is_busy() && !self.worker.snapshot().running;
+        if idle && self.is_continue && ui.time() - self.last_time > self.update_delay {
+            self.worker.send(Command::Step(self.step_size));
             self.last_time = ui.time();
         }
     }
@@ -0,0 +1,68 @@
+//! Machine words as read from xsm.
+//!
+//! A word of xsm memory or a register holds either an integer or a string.
+//! Words are parsed once where they are read, so that everything showing
+//! or computing with them agrees on which is which.
+
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum XSMWord {
+    Int(i64),
+    Str(String),
+}
+
+impl XSMWord {
+    /// A word is an integer only if xsm would print the integer the same way,
+    /// so that displaying a word gives back exactly what xsm printed.
+    /// Page table aux bits such as "0110" stay strings.
+    pub fn parse(text: &str) -> Self {
+        match text.parse::<i64>() {
+            Ok(i) if i.to_string() == text => XSMWord::Int(i),
+            _ => XSMWord::Str(text.to_owned()),
+        }
+    }
+
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            XSMWord::Int(i) => Some(*i),
+            XSMWord::Str(_) => None,
+        }
+    }
+
+    /// The word as a memory address, page number or count.
+    /// None for strings and negative integers.
+    pub fn as_addr(&self) -> Option<usize> {
+        self.as_int().filter(|&i| i >= 0).map(|i| i as usize)
+    }
+
+    /// Zero and the empty string are false in breakpoint conditions.
+    pub fn is_true(&self) -> bool {
+        match self {
+            XSMWord::Int(i) => *i != 0,
+            XSMWord::Str(s) => !s.is_empty(),
+        }
+    }
+}
+
+/// Memory xsm has not written to reads as an empty string.
+impl Default for XSMWord {
+    fn default() -> Self {
+        XSMWord::Str(String::new())
+    }
+}
+
+impl From<i64> for XSMWord {
+    fn from(i: i64) -> Self {
+        XSMWord::Int(i)
+    }
+}
+
+impl fmt::Display for XSMWord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            XSMWord::Int(i) => write!(f, "{}", i),
+            XSMWord::Str(s) => f.write_str(s),
+        }
+    }
+}
@@ -0,0 +1,397 @@
+//! Runs `XSM` on a thread of its own, so that long operations
+//! do not freeze the user interface.
+//!
+//! The interface sends commands over a channel, and the worker thread
+//! publishes a snapshot of everything the windows show after each of them.
+//! While a command runs, its progress can be read and it can be cancelled.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
+
+use crate::backend::End;
+use crate::progress::Progress;
+use crate::word::XSMWord;
+use crate::xsm::{Breakpoint, Watchpoint, XSMPageTableEntry, XSMRegs, XSM};
+
+/// Instructions shown in the Code window.
+const CODE_LINES: usize = 20;
+/// How often the machine is polled while it runs with `continue`.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+pub enum Command {
+    Step(usize),
+    StepOver,
+    StepOut,
+    Run,
+    /// Stops the machine started by Run
+    Stop,
+    GotoStep(usize),
+    StepBack,
+    GoLive,
+    SetHistoryPos(usize),
+    SetHistoryCapacity(usize),
+    /// Adds a breakpoint, or updates the one at the same address
+    SetBreakpoint {
+        addr: usize,
+        is_virtual: bool,
+        condition: String,
+        ignore_count: usize,
+    },
+    ToggleBreakpoint {
+        addr: usize,
+        is_virtual: bool,
+    },
+    SetBreakpointEnabled(usize, bool),
+    RemoveBreakpoint(usize),
+    AddWatchpoint(usize),
+    RemoveWatchpoint(usize),
+    ClearWatchpoints,
+    StartTrace(PathBuf),
+    StopTrace,
+    DismissError(usize),
+    ClearErrors,
+    /// A line of console input for the machine
+    WriteInput(String),
+    /// A line of console input for the next time the machine waits for one
+    QueueInput(String),
+    ClearQueuedInput,
+    /// Starts the session again, with the same command line if `argv` is None
+    Restart {
+        argv: Option<Vec<String>>,
+        repeat_input: bool,
+    },
+    /// Shows memory in the named window
+    ShowMem(&'static str, MemView),
+}
+
+/// A range of memory shown in a window.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemView {
+    pub addr: usize,
+    pub len: usize,
+    pub is_virtual: bool,
+    /// Read again after every command, rather than once
+    pub live: bool,
+}
+
+/// What the windows show, as of the last command the worker finished.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    /// Number of commands executed so far
+    pub executed: usize,
+    /// The command line of the session
+    pub command: String,
+    pub regs: XSMRegs,
+    pub page_table: Vec<XSMPageTableEntry>,
+    pub status: String,
+    pub output: Vec<String>,
+    pub errors: Vec<String>,
+    /// (base_addr, ip, code) as returned by `XSM::get_code`
+    pub code: (usize, usize, Vec<String>),
+    pub is_user_mode: bool,
+    pub breakpoints: Vec<Breakpoint>,
+    pub breakpoint_hit: Option<Breakpoint>,
+    pub watchpoints: Vec<Watchpoint>,
+    pub watchpoint_hits: Vec<Watchpoint>,
+    pub watch_triggered: bool,
+    pub is_next_halt: bool,
+    pub is_exception_edge: bool,
+    pub step_count: usize,
+    pub steps_exact: bool,
+    pub running: bool,
+    /// Set once the machine has halted or xsm has exited
+    pub end: Option<End>,
+    pub viewing_history: bool,
+    pub history_len: usize,
+    pub history_pos: usize,
+    pub history_capacity: usize,
+    pub tracing: bool,
+    pub replay_path: Option<PathBuf>,
+    pub waiting_input: bool,
+    /// Console input written so far, with the step count it was written at
+    pub inputs: Vec<(usize, String)>,
+    pub queued_input: Vec<String>,
+    /// The first address and the words shown by each memory window
+    pub mem: HashMap<&'static str, (usize, Vec<XSMWord>)>,
+    /// Why the last breakpoint condition was not accepted
+    pub bp_error: Option<String>,
+    /// Why the last trace file could not be created
+    pub trace_error: Option<String>,
+}
+
+pub struct Worker {
+    /// Dropped to stop the worker thread
+    commands: Option<Sender<Command>>,
+    snapshots: Receiver<Snapshot>,
+    progress: Arc<Progress>,
+    snapshot: Snapshot,
+    /// Number of commands sent so far
+    sent: usize,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Worker {
+    pub fn spawn(xsm: XSM) -> Worker {
+        let progress = xsm.get_progress();
+        let mut engine = Engine {
+            xsm,
+            executed: 0,
+            mem_views: HashMap::new(),
+            mem: HashMap::new(),
+            bp_error: None,
+            trace_error: None,
+        };
+        let snapshot = engine.snapshot();
+        let (commands_tx, commands_rx) = crossbeam_channel::unbounded();
+        let (snapshots_tx, snapshots_rx) = crossbeam_channel::unbounded();
+        let thread = std::thread::spawn(move || engine.serve(commands_rx, snapshots_tx));
+        Worker {
+            commands: Some(commands_tx),
+            snapshots: snapshots_rx,
+            progress,
+            snapshot,
+            sent: 0,
+            thread: Some(thread),
+        }
+    }
+
+    /// Queues a command for the worker thread.
+    pub fn send(&mut self, command: Command) {
+        if let Some(commands) = &self.commands {
+            if commands.send(command).is_ok() {
+                self.sent += 1;
+            }
+        }
+    }
+
+    /// Takes the latest snapshot published by the worker thread, if any.
+    /// Call once per frame.
+    pub fn update(&mut self) {
+        while let Ok(snapshot) = self.snapshots.try_recv() {
+            self.snapshot = snapshot;
+        }
+    }
+
+    pub fn snapshot(&self) -> &Snapshot {
+        &self.snapshot
+    }
+
+    /// True until every command sent has been executed.
+    pub fn is_busy(&self) -> bool {
+        self.snapshot.executed < self.sent
+    }
+
+    /// Instructions executed by the command being run,
+    /// and out of how many if known.
+    pub fn get_progress(&self) -> (usize, Option<usize>) {
+        self.progress.get()
+    }
+
+    /// Stops the command being run at the next chunk of instructions.
+    /// Commands still queued are cancelled too.
+    pub fn cancel(&self) {
+        self.progress.cancel(self.sent);
+    }
+}
+
+/// Waits for the worker thread, so that the backend is dropped
+/// (and xsm's working directory removed) before xdb exits.
+impl Drop for Worker {
+    fn drop(&mut self) {
+        self.cancel();
+        self.commands = None;
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// What the worker thread owns.
+struct Engine {
+    xsm: XSM,
+    executed: usize,
+    mem_views: HashMap<&'static str, MemView>,
+    mem: HashMap<&'static str, (usize, Vec<XSMWord>)>,
+    bp_error: Option<String>,
+    trace_error: Option<String>,
+}
+
+impl Engine {
+    /// Executes commands until the interface drops its end of the channel.
+    /// While the machine runs, it is polled between commands.
+    fn serve(mut self, commands: Receiver<Command>, snapshots: Sender<Snapshot>) {
+        loop {
+            let command = if self.xsm.is_running() {
+                match commands.recv_timeout(POLL_INTERVAL) {
+                    Ok(command) => command,
+                    Err(RecvTimeoutError::Timeout) => {
+                        if self.xsm.poll() && snapshots.send(self.snapshot()).is_err() {
+                            return;
+                        }
+                        continue;
+                    }
+                    Err(RecvTimeoutError::Disconnected) => return,
+                }
+            } else {
+                match commands.recv() {
+                    Ok(command) => command,
+                    Err(_) => return,
+                }
+            };
+            self.execute(command);
+            self.executed += 1;
+            if snapshots.send(self.snapshot()).is_err() {
+                return;
+            }
+        }
+    }
+
+    fn execute(&mut self, command: Command) {
+        let xsm = &mut self.xsm;
+        let total = match command {
+            Command::Step(n) => Some(n),
+            Command::GotoStep(target) => {
+                let step = xsm.get_step_count();
+                Some(if target >= step {
+                    target - step
+                } else {
+                    target
+                })
+            }
+            _ => None,
+        };
+        xsm.get_progress().start(total);
+        match command {
+            Command::Step(n) => {
+                xsm.step(n);
+            }
+            Command::StepOver => {
+                xsm.step_over();
+            }
+            Command::StepOut => {
+                xsm.step_out();
+            }
+            Command::Run => xsm.run(),
+            Command::Stop => xsm.interrupt(),
+            Command::GotoStep(target) => xsm.goto_step(target),
+            Command::StepBack => {
+                xsm.step_back();
+            }
+            Command::GoLive => xsm.go_live(),
+            Command::SetHistoryPos(pos) => xsm.set_history_pos(pos),
+            Command::SetHistoryCapacity(capacity) => xsm.set_history_capacity(capacity),
+            Command::SetBreakpoint {
+                addr,
+                is_virtual,
+                condition,
+                ignore_count,
+            } => {
+                self.bp_error = xsm
+                    .set_breakpoint(addr, is_virtual, &condition, ignore_count)
+                    .err()
+                    .map(|e| e.to_string());
+            }
+            Command::ToggleBreakpoint { addr, is_virtual } => {
+                xsm.toggle_breakpoint(addr, is_virtual)
+            }
+            Command::SetBreakpointEnabled(i, enabled) => xsm.set_breakpoint_enabled(i, enabled),
+            Command::RemoveBreakpoint(i) => xsm.remove_breakpoint(i),
+            Command::AddWatchpoint(addr) => xsm.add_watchpoint(addr),
+            Command::RemoveWatchpoint(i) => xsm.remove_watchpoint(i),
+            Command::ClearWatchpoints => xsm.clear_watchpoints(),
+            Command::StartTrace(path) => {
+                self.trace_error = xsm.start_trace(&path).err().map(|e| e.to_string());
+            }
+            Command::StopTrace => xsm.stop_trace(),
+            Command::DismissError(i) => xsm.dismiss_error(i),
+            Command::ClearErrors => xsm.clear_errors(),
+            Command::WriteInput(line) => xsm.write_input(&line),
+            Command::QueueInput(line) => xsm.queue_input(&line),
+            Command::ClearQueuedInput => xsm.clear_queued_input(),
+            Command::Restart { argv, repeat_input } => {
+                xsm.restart(argv.as_deref(), repeat_input);
+                // Memory windows keep what they show, read from the new machine.
+                let views: Vec<(&'static str, MemView)> = self
+                    .mem_views
+                    .iter()
+                    .map(|(&window, view)| (window, view.clone()))
+                    .collect();
+                for (window, view) in views {
+                    let words = self.read_mem(&view);
+                    self.mem.insert(window, words);
+                }
+            }
+            Command::ShowMem(window, view) => {
+                let words = self.read_mem(&view);
+                self.mem.insert(window, words);
+                self.mem_views.insert(window, view);
+            }
+        }
+    }
+
+    fn read_mem(&mut self, view: &MemView) -> (usize, Vec<XSMWord>) {
+        let end = view.addr + view.len;
+        let words = if view.is_virtual {
+            self.xsm.read_mem_range_vir(view.addr, end)
+        } else {
+            self.xsm.read_mem_range(view.addr, end)
+        };
+        (view.addr, words)
+    }
+
+    /// Live memory windows are read again for every snapshot.
+    fn snapshot(&mut self) -> Snapshot {
+        let live: Vec<(&'static str, MemView)> = self
+            .mem_views
+            .iter()
+            .filter(|(_, view)| view.live)
+            .map(|(&window, view)| (window, view.clone()))
+            .collect();
+        for (window, view) in live {
+            let words = self.read_mem(&view);
+            self.mem.insert(window, words);
+        }
+
+        let xsm = &mut self.xsm;
+        let code = xsm.get_code(CODE_LINES);
+        Snapshot {
+            executed: self.executed,
+            command: xsm.get_command().to_owned(),
+            regs: xsm.get_regs().clone(),
+            page_table: xsm.get_page_table().clone(),
+            status: xsm.get_status().to_owned(),
+            output: xsm.get_output().to_vec(),
+            errors: xsm.get_errors().iter().map(|e| e.to_string()).collect(),
+            code,
+            is_user_mode: xsm.is_user_mode(),
+            breakpoints: xsm.get_breakpoints().clone(),
+            breakpoint_hit: xsm.get_breakpoint_hit().cloned(),
+            watchpoints: xsm.get_watchpoints().clone(),
+            watchpoint_hits: xsm.get_watchpoint_hits().cloned().collect(),
+            watch_triggered: xsm.is_watch_triggered(),
+            is_next_halt: xsm.is_next_halt(),
+            is_exception_edge: xsm.is_exception_edge(),
+            step_count: xsm.get_step_count(),
+            steps_exact: xsm.is_step_count_exact(),
+            running: xsm.is_running(),
+            end: xsm.get_end(),
+            viewing_history: xsm.is_viewing_history(),
+            history_len: xsm.get_history_len(),
+            history_pos: xsm.get_history_pos(),
+            history_capacity: xsm.get_history_capacity(),
+            tracing: xsm.is_tracing(),
+            replay_path: xsm.get_replay_path().map(|path| path.to_owned()),
+            waiting_input: xsm.is_waiting_input(),
+            inputs: xsm.get_inputs().to_vec(),
+            queued_input: xsm.get_queued_input().iter().cloned().collect(),
+            mem: self.mem.clone(),
+            bp_error: self.bp_error.clone(),
+            trace_error: self.trace_error.clone(),
+        }
+    }
+}
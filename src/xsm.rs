@@ -1,39 +1,163 @@
-use std::fs;
-use std::io::{BufRead, BufReader, Read, Write};
-use std::process::{Child, ChildStdin, ChildStdout, Command};
-use std::thread::sleep;
-use std::time::Duration;
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::backend::{BackendError, DebugBackend, End, Stop};
+use crate::emulator::{self, Machine};
+use crate::expr::{self, Expr, ExprError};
+use crate::history::History;
+use crate::parse;
+use crate::process::{self, SharedFiles, Timeouts, XSMProcess};
+use crate::progress::Progress;
+use crate::replay::TraceReplay;
+use crate::trace::TraceWriter;
+use crate::word::XSMWord;
 
 const XSM_PAGE_LEN: usize = 512;
+/// Number of past states kept for stepping back.
+const HISTORY_LEN: usize = 1000;
+/// A step back that replays xsm steps this many instructions before its
+/// target one at a time, so that the next step backs are in the history.
+const STEP_BACK_RECORDED: usize = 100;
+/// Step over and step out give up after this many instructions.
+const STEP_UNTIL_LIMIT: usize = 100_000;
+/// Large steps are sent to the backend this many instructions at a time,
+/// so that they can be cancelled and do not run into the response timeout.
+const STEP_CHUNK: usize = 1000;
+/// A checkpoint of the registers is kept every this many steps,
+/// for replays to check that they follow the original run.
+const CHECKPOINT_INTERVAL: usize = 10_000;
 
 pub struct XSM {
-    xsm: Child,
-    stdin: ChildStdin,
-    stdout: crossbeam_channel::Receiver<String>,
+    command: String,
+    /// Used again when xsm is restarted with another command line
+    timeouts: Timeouts,
+    /// Those of the xsm child, also used when restarted
+    /// with another command line
+    files: Option<SharedFiles>,
+    backend: Box<dyn DebugBackend>,
+    state: MachineState,
+    history: History<MachineState>,
+    errors: Vec<XSMError>,
+    output: Vec<String>,
+    /// Set once the machine has halted or xsm has exited
+    end: Option<End>,
+    last_code: (usize, usize, Vec<String>),
+    /// Why the code could not be read last time, reported once
+    /// for as long as it stays the same, since the code is read
+    /// after every command
+    code_error: Option<String>,
+    /// Why the page table could not be read last time, reported once
+    /// for as long as it stays the same, since it is read after every step
+    page_table_error: Option<String>,
+    breakpoints: Vec<Breakpoint>,
+    breakpoint_hit: Option<usize>,
+    watchpoints: Vec<Watchpoint>,
+    watchpoint_hits: Vec<usize>,
+    /// The watched address whose write stopped the last step
+    watch_addr: Option<usize>,
+    running: bool,
+    /// The running machine was asked to stop, so it is not run on past an INI
+    interrupted: bool,
+    /// Console input fed to the machine, with the step count it was fed at.
+    /// Replaying these makes a restarted xsm reach the same states.
+    inputs: Vec<(usize, String)>,
+    /// Kept while the step count is exact, and carried into replays
+    checkpoints: Vec<Checkpoint>,
+    /// Lines written one at a time whenever the machine waits for input
+    queued_input: VecDeque<String>,
+    /// IN instructions stepped over whose line has not been written yet.
+    /// xsm raises the console interrupt once it has read the line.
+    /// Only an IN that ends a step is seen, as the status does not tell
+    /// what was executed in between.
+    awaiting_input: usize,
+    /// True once the line for the INI at IP has been written
+    ini_fed: bool,
+    trace: Option<TraceWriter>,
+    progress: Arc<Progress>,
+    /// A second machine replayed to a past state, for reading the memory
+    /// of that state. Kept to be stepped on to later states.
+    past: Option<Box<XSM>>,
+}
+
+/// Everything the windows show about the machine at one point.
+/// Past states are kept in the history for stepping back.
+#[derive(Debug, Clone)]
+struct MachineState {
     mode: Mode,
     regs: XSMRegs,
     page_table: Vec<XSMPageTableEntry>,
-    errors: Vec<String>,
-    output: Vec<String>,
+    status: String,
+    next_instr: String,
     is_next_halt: bool,
     is_exception_edge: bool,
-    halted: bool,
-    status: String,
-    last_code: (usize, usize, Vec<String>),
+    step_count: usize,
+    steps_exact: bool,
+    output_len: usize,
+    /// Memory pages read while in this state, by physical page.
+    /// For the live state they are also the cache of the machine's memory,
+    /// cleared whenever it steps or runs.
+    pages: HashMap<usize, Arc<Vec<XSMWord>>>,
 }
 
-#[derive(Debug)]
-pub enum XSMError {
-    PTBRInvalid,
-    PTLRInvalid,
-    InvalidPageTableEntry {
-        index: usize,
-        entry: XSMPageTableEntry,
-    },
+/// The registers after `step` instructions, with `inputs` lines
+/// of console input written before them.
+#[derive(Debug, Clone)]
+struct Checkpoint {
+    step: usize,
+    inputs: usize,
+    regs: XSMRegs,
 }
 
+/// Errors shown to the user. None of them end the debugging session.
 #[derive(Debug)]
-enum XSMInternalError {
+pub enum XSMError {
+    /// The machine could not be stepped, run or given a command
+    Backend(BackendError),
+    ReadRegs(BackendError),
+    ReadMemPage {
+        page: usize,
+        source: BackendError,
+    },
+    Restart {
+        command: String,
+        source: BackendError,
+    },
+    /// Writing the trace failed, so it was stopped
+    Trace(io::Error),
+    /// The condition of a breakpoint could not be evaluated
+    Condition {
+        condition: String,
+        error: ExprError,
+    },
+    /// Step over or step out did not return in time
+    GaveUp {
+        action: &'static str,
+        stepped: usize,
+    },
+    RunWhileTracing,
+    /// Going back past the history replays steps already in the trace
+    GotoWhileTracing,
+    /// A replay did not reach the registers of the original run
+    ReplayDiverged {
+        step: usize,
+    },
+    /// Console input was written while the machine was not reading any,
+    /// so xsm would have taken it for a debugger command
+    NotWaitingForInput,
+    /// Watchpoints were asked of a backend without memory
+    NoMemory,
+    IPInvalid(XSMWord),
+    /// The page of IP is not in the page table
+    IPNotPaged {
+        ip: usize,
+        page: usize,
+    },
+    PTBRInvalid(XSMWord),
+    PTLRInvalid(XSMWord),
     InvalidPageTableEntry {
         index: usize,
         entry: XSMPageTableEntry,
@@ -45,155 +169,1301 @@ enum XSMInternalError {
     VirtualMemoryOutOfBounds {
         addr: usize,
     },
-    DebugModeNotEntered {
-        lines_read: Vec<String>,
-    },
+}
+
+impl fmt::Display for XSMError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            XSMError::Backend(e) => write!(f, "{}", e),
+            XSMError::ReadRegs(e) => write!(f, "Failed to read registers: {}", e),
+            XSMError::ReadMemPage { page, source } => {
+                write!(f, "Failed to read memory page {}: {}", page, source)
+            }
+            XSMError::Restart { command, source } => {
+                write!(f, "Failed to restart '{}': {}", command.trim(), source)
+            }
+            XSMError::Trace(e) => write!(f, "Trace stopped: {}", e),
+            XSMError::Condition { condition, error } => {
+                write!(f, "Breakpoint condition '{}': {}", condition, error)
+            }
+            XSMError::GaveUp { action, stepped } => {
+                write!(f, "{}: gave up after {} instructions", action, stepped)
+            }
+            XSMError::RunWhileTracing => write!(f, "Run cannot be traced, stop the trace first"),
+            XSMError::GotoWhileTracing => write!(
+                f,
+                "Going back past the history cannot be traced, stop the trace first"
+            ),
+            XSMError::ReplayDiverged { step } => write!(
+                f,
+                "The replay differs from the original run at step {}, \
+                 the disk or the program may have changed",
+                step
+            ),
+            XSMError::NotWaitingForInput => {
+                write!(f, "The machine is not waiting for console input")
+            }
+            XSMError::NoMemory => write!(
+                f,
+                "Watchpoints need memory, which this backend does not have"
+            ),
+            XSMError::IPInvalid(ip) => write!(f, "IP: '{}' is not an unsigned number", ip),
+            XSMError::IPNotPaged { ip, page } => {
+                write!(f, "IP: {}, Page: {} not found in page table", ip, page)
+            }
+            XSMError::PTBRInvalid(ptbr) => write!(f, "PTBR: '{}' is invalid", ptbr),
+            XSMError::PTLRInvalid(ptlr) => write!(f, "PTLR: '{}' is invalid", ptlr),
+            XSMError::InvalidPageTableEntry { index, entry } => write!(
+                f,
+                "Page table entry {} is invalid: {} [{}]",
+                index, entry.phy, entry.aux
+            ),
+            XSMError::VirtualMemoryNotPaged { page, entry } => write!(
+                f,
+                "Virtual page {} is not in memory: {} [{}]",
+                page, entry.phy, entry.aux
+            ),
+            XSMError::VirtualMemoryOutOfBounds { addr } => {
+                write!(f, "Virtual address {} is past the page table", addr)
+            }
+        }
+    }
+}
+
+impl Error for XSMError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            XSMError::Backend(e) | XSMError::ReadRegs(e) => Some(e),
+            XSMError::ReadMemPage { source, .. } | XSMError::Restart { source, .. } => Some(source),
+            XSMError::Trace(e) => Some(e),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct XSMPageTableEntry {
-    pub phy: String,
-    pub aux: String,
+    pub phy: XSMWord,
+    pub aux: XSMWord,
 }
 
-#[derive(Debug)]
+/// A breakpoint on the address of the next instruction.
+/// Virtual breakpoints only match in user mode.
+/// If there is a condition, the breakpoint is hit only when it is true.
+/// The first `ignore_count` hits do not stop the machine.
+#[derive(Debug, Clone)]
+pub struct Breakpoint {
+    pub addr: usize,
+    pub is_virtual: bool,
+    pub enabled: bool,
+    pub hits: usize,
+    pub condition: Option<Expr>,
+    pub ignore_count: usize,
+}
+
+/// A physical memory address watched by xsm's `watch` command.
+/// `old_value` is the value before the last write that triggered it.
+#[derive(Debug, Clone)]
+pub struct Watchpoint {
+    pub addr: usize,
+    pub value: XSMWord,
+    pub old_value: XSMWord,
+    pub hits: usize,
+}
+
+#[derive(Debug, Clone)]
 pub enum Mode {
     Kernel,
     User,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default, Clone, PartialEq)]
 pub struct XSMRegs {
-    pub r: [String; 20],
-    pub p: [String; 4],
-    pub bp: String,
-    pub sp: String,
-    pub ip: String,
-    pub ptbr: String,
-    pub ptlr: String,
-    pub eip: String,
-    pub ec: String,
-    pub epn: String,
-    pub ema: String,
+    pub r: [XSMWord; 20],
+    pub p: [XSMWord; 4],
+    pub bp: XSMWord,
+    pub sp: XSMWord,
+    pub ip: XSMWord,
+    pub ptbr: XSMWord,
+    pub ptlr: XSMWord,
+    pub eip: XSMWord,
+    pub ec: XSMWord,
+    pub epn: XSMWord,
+    pub ema: XSMWord,
 }
 
-impl Default for XSMRegs {
-    fn default() -> Self {
-        Self {
-            r: Default::default(),
-            p: Default::default(),
-            bp: Default::default(),
-            sp: Default::default(),
-            ip: Default::default(),
-            ptbr: Default::default(),
-            ptlr: Default::default(),
-            eip: Default::default(),
-            ec: Default::default(),
-            epn: Default::default(),
-            ema: Default::default(),
+impl XSMRegs {
+    /// Register names and values, in the order xsm prints them.
+    pub fn named(&self) -> impl Iterator<Item = (String, &XSMWord)> {
+        let r = (self.r.iter().enumerate()).map(|(i, val)| (format!("R{}", i), val));
+        let p = (self.p.iter().enumerate()).map(|(i, val)| (format!("P{}", i), val));
+        let others = vec![
+            ("BP", &self.bp),
+            ("SP", &self.sp),
+            ("IP", &self.ip),
+            ("PTBR", &self.ptbr),
+            ("PTLR", &self.ptlr),
+            ("EIP", &self.eip),
+            ("EC", &self.ec),
+            ("EPN", &self.epn),
+            ("EMA", &self.ema),
+        ]
+        .into_iter()
+        .map(|(name, val)| (name.to_owned(), val));
+        r.chain(p).chain(others)
+    }
+
+    /// The register with the given name, as printed by `named`.
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut XSMWord> {
+        if let Some(i) = name.strip_prefix('R') {
+            return i.parse::<usize>().ok().and_then(move |i| self.r.get_mut(i));
+        }
+        if let Some(i) = name.strip_prefix('P') {
+            if let Ok(i) = i.parse::<usize>() {
+                return self.p.get_mut(i);
+            }
+        }
+        match name {
+            "BP" => Some(&mut self.bp),
+            "SP" => Some(&mut self.sp),
+            "IP" => Some(&mut self.ip),
+            "PTBR" => Some(&mut self.ptbr),
+            "PTLR" => Some(&mut self.ptlr),
+            "EIP" => Some(&mut self.eip),
+            "EC" => Some(&mut self.ec),
+            "EPN" => Some(&mut self.epn),
+            "EMA" => Some(&mut self.ema),
+            _ => None,
         }
     }
 }
 
 impl XSM {
-    pub fn spawn_new(command: &str) -> Result<XSM, ()> {
-        let mut stdbuf_args = vec!["--output=0"];
-        stdbuf_args.extend(command.split_whitespace());
-
-        let mut xsm_process = Command::new("stdbuf")
-            .args(&stdbuf_args)
-            .stdin(std::process::Stdio::piped())
-            .stdout(std::process::Stdio::piped())
-            .spawn()
-            .map_err(|_| {
-                println!("Error: Failed to launch command.");
-            })?;
-
-        let stdout = xsm_process.stdout.take().expect("Failed to get stdout");
-        let stdin = xsm_process.stdin.take().expect("Failed to get stdin");
-        let (stdout_tx, stdout_rx) = crossbeam_channel::bounded(100);
-        std::thread::spawn(move || {
-            let mut buf_reader = BufReader::new(stdout);
-            let mut buf = String::new();
-            loop {
-                buf_reader.read_line(&mut buf);
-                stdout_tx.send(buf.clone());
-                buf.clear();
-            }
-        });
+    /// `argv` is the xsm program followed by its arguments.
+    pub fn spawn_new(argv: &[String]) -> Result<XSM, BackendError> {
+        XSM::spawn_with_timeouts(argv, Timeouts::default())
+    }
+
+    pub fn spawn_with_timeouts(argv: &[String], timeouts: Timeouts) -> Result<XSM, BackendError> {
+        let files = SharedFiles::current().map_err(BackendError::WorkDir)?;
+        XSM::spawn_with_files(argv, timeouts, files)
+    }
+
+    /// Spawns xsm with `files` in place of the disk and library
+    /// of the current directory.
+    pub fn spawn_with_files(
+        argv: &[String],
+        timeouts: Timeouts,
+        files: SharedFiles,
+    ) -> Result<XSM, BackendError> {
+        let process = XSMProcess::spawn_with_files(argv, timeouts, files.clone())?;
+        let mut xsm = XSM::with_backend(&process::command_line(argv), Box::new(process));
+        xsm.timeouts = timeouts;
+        xsm.files = Some(files);
+        Ok(xsm)
+    }
+
+    /// Opens a trace recorded with `start_trace` to be stepped through
+    /// in place of a live xsm. Memory is not available.
+    pub fn open_trace(path: &Path) -> Result<XSM, BackendError> {
+        let replay = TraceReplay::open(path)?;
+        Ok(XSM::with_backend(&path.to_string_lossy(), Box::new(replay)))
+    }
+
+    /// Runs the machine in the built-in emulator instead of xsm.
+    pub fn emulate(command: &str, config: emulator::Config) -> Result<XSM, BackendError> {
+        let machine = Machine::new(config)?;
+        Ok(XSM::with_backend(command, Box::new(machine)))
+    }
 
+    /// `command` describes the backend in error messages.
+    pub fn with_backend(command: &str, mut backend: Box<dyn DebugBackend>) -> XSM {
+        let stop = backend.initial_stop();
         let mut xsm = XSM {
-            xsm: xsm_process,
-            stdin,
-            stdout: stdout_rx,
-            mode: Mode::Kernel,
-            regs: XSMRegs::default(),
-            page_table: Vec::new(),
+            command: command.to_owned(),
+            timeouts: Timeouts::default(),
+            files: None,
+            backend,
+            state: MachineState {
+                mode: Mode::Kernel,
+                regs: XSMRegs::default(),
+                page_table: Vec::new(),
+                status: String::new(),
+                next_instr: String::new(),
+                is_next_halt: false,
+                is_exception_edge: true,
+                step_count: 0,
+                steps_exact: true,
+                output_len: 0,
+                pages: HashMap::new(),
+            },
+            history: History::new(HISTORY_LEN),
             errors: Vec::new(),
             output: Vec::new(),
-            is_next_halt: false,
-            is_exception_edge: true,
-            halted: false,
-            status: String::new(),
+            end: None,
             last_code: (0, 0, Vec::new()),
+            code_error: None,
+            page_table_error: None,
+            breakpoints: Vec::new(),
+            breakpoint_hit: None,
+            watchpoints: Vec::new(),
+            watchpoint_hits: Vec::new(),
+            watch_addr: None,
+            running: false,
+            interrupted: false,
+            inputs: Vec::new(),
+            checkpoints: Vec::new(),
+            queued_input: VecDeque::new(),
+            awaiting_input: 0,
+            ini_fed: false,
+            trace: None,
+            progress: Arc::default(),
+            past: None,
+        };
+        xsm.load_state(stop);
+        xsm
+    }
+
+    pub fn is_trace_replay(&self) -> bool {
+        self.backend.replay_path().is_some()
+    }
+
+    /// The trace file being viewed, if not running a live xsm.
+    pub fn get_replay_path(&self) -> Option<&Path> {
+        self.backend.replay_path()
+    }
+
+    /// Executes up to n instructions, stopping early if the machine halts,
+    /// an enabled breakpoint is reached or a watchpoint is triggered.
+    /// Returns the number of instructions executed.
+    /// While viewing a past state, moves forward through the history first.
+    pub fn step(&mut self, n: usize) -> usize {
+        if self.running {
+            return 0;
+        }
+        let mut replayed = 0;
+        while replayed < n && self.history.forward() {
+            replayed += 1;
+        }
+        if replayed > 0 {
+            return replayed;
+        }
+        self.breakpoint_hit = None;
+        self.watchpoint_hits.clear();
+        self.watch_addr = None;
+        if !self.breakpoints.iter().any(|bp| bp.enabled) && self.watchpoints.is_empty() {
+            return self._step(n);
+        }
+
+        // xsm has no breakpoints of its own, so step one instruction
+        // at a time and check IP after each.
+        // Watchpoints make xsm stop early, so they are stepped the same way
+        // to keep the step count exact.
+        let mut stepped = 0;
+        while stepped < n {
+            if self._step(1) == 0 {
+                break;
+            }
+            stepped += 1;
+            if self.watch_addr.is_some() {
+                self._update_watchpoints();
+                break;
+            }
+            if self.is_halted() || self._check_breakpoints() {
+                break;
+            }
+        }
+        stepped
+    }
+
+    /// Goes to the state after `target` instructions from the start.
+    /// Uses the history if the state is there and steps forward if it is ahead.
+    /// Otherwise xsm is restarted with the same command line and replayed
+    /// to it, feeding the console input recorded so far and checking
+    /// the registers at the checkpoints on the way.
+    /// Breakpoints and watchpoints do not stop it on the way.
+    /// Restarting is refused while a trace is recorded, as the replayed
+    /// steps would be recorded a second time.
+    pub fn goto_step(&mut self, target: usize) {
+        self._goto_step(target, 0);
+    }
+
+    /// Like `goto_step`, with the last `recorded` instructions of a replay
+    /// stepped one at a time, so that each gets a state in the history.
+    fn _goto_step(&mut self, target: usize, recorded: usize) {
+        if self.running {
+            return;
+        }
+        let in_history = self
+            .history
+            .iter()
+            .position(|state| state.steps_exact && state.step_count == target);
+        self.history.seek(in_history);
+        if in_history.is_some() {
+            return;
+        }
+
+        if !self.is_halted() && self.state.steps_exact && target >= self.state.step_count {
+            if target > self.state.step_count {
+                self._step(target - self.state.step_count);
+            }
+            return;
+        }
+
+        if self.is_tracing() {
+            self.errors.push(XSMError::GotoWhileTracing);
+            return;
+        }
+        if let Err(source) = self._respawn() {
+            self.errors.push(XSMError::Restart {
+                command: self.command.clone(),
+                source,
+            });
+            return;
+        }
+        // The queue is for input past the recorded one.
+        let queued = std::mem::take(&mut self.queued_input);
+        self._replay_to(target, recorded);
+        self.queued_input = queued;
+        self._feed_queued();
+    }
+
+    /// Steps the machine on to `target`, writing the recorded
+    /// console input at the step counts it was written at.
+    /// The last `recorded` instructions are stepped one at a time,
+    /// so that each gets a state in the history.
+    /// Stops early with an error if a checkpoint is not matched.
+    fn _replay_to(&mut self, target: usize, recorded: usize) {
+        let start = self.state.step_count;
+        let mut inputs: VecDeque<(usize, String)> = self
+            .inputs
+            .iter()
+            .filter(|(step, _)| (start..target).contains(step))
+            .cloned()
+            .collect();
+        let mut checkpoints: VecDeque<Checkpoint> = self
+            .checkpoints
+            .iter()
+            .filter(|checkpoint| (start..=target).contains(&checkpoint.step))
+            .cloned()
+            .collect();
+        let mut fed = self.inputs.iter().filter(|(step, _)| *step < start).count();
+        let recorded_from = target.saturating_sub(recorded);
+        loop {
+            let step = self.state.step_count;
+            // Checkpoints are kept before the input written at their step.
+            if checkpoints
+                .front()
+                .is_some_and(|checkpoint| checkpoint.step <= step)
+            {
+                let checkpoint = checkpoints.pop_front().unwrap();
+                if checkpoint.step != step
+                    || checkpoint.inputs != fed
+                    || checkpoint.regs != self.state.regs
+                {
+                    self.errors.push(XSMError::ReplayDiverged { step });
+                    return;
+                }
+            }
+            while inputs.front().is_some_and(|(at, _)| *at <= step) {
+                let (_, line) = inputs.pop_front().unwrap();
+                self._feed_input(&line);
+                fed += 1;
+            }
+            let next = [
+                inputs.front().map(|(at, _)| *at),
+                checkpoints.front().map(|checkpoint| checkpoint.step),
+                Some(if step < recorded_from {
+                    recorded_from
+                } else {
+                    step + 1
+                }),
+            ]
+            .iter()
+            .flatten()
+            .fold(target, |next, &at| next.min(at));
+            if next <= step {
+                return;
+            }
+            if self._step(next - step) < next - step {
+                return;
+            }
+        }
+    }
+
+    /// Replaces the xsm child with a fresh one running the same command line.
+    /// Breakpoints, watchpoints, the recorded console input
+    /// and the checkpoints are kept.
+    fn _respawn(&mut self) -> Result<(), BackendError> {
+        let mut fresh = XSM::with_backend(&self.command, self.backend.restart()?);
+        // A replayed trace brings its recorded input back as it is stepped.
+        if !self.is_trace_replay() {
+            fresh.inputs = std::mem::take(&mut self.inputs);
+        }
+        fresh.checkpoints = std::mem::take(&mut self.checkpoints);
+        fresh.queued_input = std::mem::take(&mut self.queued_input);
+        self._replace(fresh);
+        Ok(())
+    }
+
+    /// Starts a new session from the beginning, with the same command line
+    /// or with `argv`, the old xsm being killed once the new one is up.
+    /// The step count, output and errors start afresh, while breakpoints,
+    /// watchpoints and the history capacity are kept. With `repeat_input`,
+    /// the console input of the old session is queued again.
+    /// A trace being recorded is stopped.
+    pub fn restart(&mut self, argv: Option<&[String]>, repeat_input: bool) {
+        let fresh = match argv {
+            Some(argv) => match &self.files {
+                Some(files) => XSM::spawn_with_files(argv, self.timeouts, files.clone()),
+                None => XSM::spawn_with_timeouts(argv, self.timeouts),
+            },
+            None => self
+                .backend
+                .restart()
+                .map(|backend| XSM::with_backend(&self.command, backend)),
+        };
+        let mut fresh = match fresh {
+            Ok(fresh) => fresh,
+            Err(source) => {
+                self.errors.push(XSMError::Restart {
+                    command: self.command.clone(),
+                    source,
+                });
+                return;
+            }
+        };
+        self.stop_trace();
+        if repeat_input && !self.is_trace_replay() {
+            let written = self.inputs.drain(..).map(|(_, line)| line);
+            fresh.queued_input = written.chain(self.queued_input.drain(..)).collect();
+        }
+        for bp in &mut self.breakpoints {
+            bp.hits = 0;
+        }
+        self._replace(fresh);
+        self._feed_queued();
+    }
+
+    /// Replaces this machine with `fresh`, keeping the breakpoints,
+    /// watchpoints and history capacity.
+    fn _replace(&mut self, mut fresh: XSM) {
+        fresh.breakpoints = std::mem::take(&mut self.breakpoints);
+        fresh.timeouts = self.timeouts;
+        fresh.files = fresh.files.take().or_else(|| self.files.take());
+        fresh.progress = self.progress.clone();
+        fresh.history.set_capacity(self.history.capacity());
+        let watched: Vec<usize> = self.watchpoints.iter().map(|wp| wp.addr).collect();
+        *self = fresh;
+        for addr in watched {
+            self.add_watchpoint(addr);
+        }
+    }
+
+    /// The command line of the session, as shown to the user.
+    pub fn get_command(&self) -> &str {
+        &self.command
+    }
+
+    /// Writes a line of console input for the machine to read, and records it
+    /// for replays. The line is only accepted while the machine is waiting
+    /// for one or running, since xsm reads its debugger commands
+    /// from the same stdin.
+    pub fn write_input(&mut self, line: &str) {
+        if !self.running && !self.is_waiting_input() {
+            self.errors.push(XSMError::NotWaitingForInput);
+            return;
+        }
+        self.inputs.push((self.state.step_count, line.to_owned()));
+        self._feed_input(line);
+    }
+
+    /// True while the machine cannot go on without console input:
+    /// an IN was stepped over and its line is still to be read,
+    /// or the next instruction is INI.
+    pub fn is_waiting_input(&self) -> bool {
+        if self.is_halted() || self.running || self.is_trace_replay() {
+            return false;
+        }
+        self._is_blocked_on_input()
+    }
+
+    /// Console input written so far, with the step count it was written at.
+    pub fn get_inputs(&self) -> &[(usize, String)] {
+        &self.inputs
+    }
+
+    /// Queues a line of console input, written as soon as the machine
+    /// waits for input and no line queued before it is left.
+    pub fn queue_input(&mut self, line: &str) {
+        self.queued_input.push_back(line.to_owned());
+        self._feed_queued();
+    }
+
+    pub fn get_queued_input(&self) -> &VecDeque<String> {
+        &self.queued_input
+    }
+
+    pub fn clear_queued_input(&mut self) {
+        self.queued_input.clear();
+    }
+
+    /// Writes queued lines for as long as the machine waits for input.
+    /// Returns true if any was written.
+    fn _feed_queued(&mut self) -> bool {
+        let mut fed = false;
+        while self.is_waiting_input() {
+            let line = match self.queued_input.pop_front() {
+                Some(line) => line,
+                None => break,
+            };
+            self.write_input(&line);
+            fed = true;
+        }
+        fed
+    }
+
+    /// Stepping on after an IN, or stepping an INI, without input would
+    /// leave xsm blocked on stdin, where it would take the next debugger
+    /// command for the line.
+    fn _is_blocked_on_input(&self) -> bool {
+        if self.is_trace_replay() {
+            return false;
+        }
+        self.awaiting_input > 0 || (!self.ini_fed && self._live_opcode() == "INI")
+    }
+
+    fn _live_opcode(&self) -> &str {
+        parse::opcode(&self.state.next_instr)
+    }
+
+    /// Writes a line of console input to the backend,
+    /// for the pending IN if there is one, else for the INI at IP.
+    /// The line goes into the trace before the next instruction.
+    fn _feed_input(&mut self, line: &str) {
+        if let Err(e) = self.backend.write_input(line) {
+            self._push_error(XSMError::Backend(e));
+            return;
+        }
+        if let Some(trace) = &mut self.trace {
+            trace.input(line);
+        }
+        if self.awaiting_input > 0 {
+            self.awaiting_input -= 1;
+        } else {
+            self.ini_fed = true;
+        }
+    }
+
+    /// Runs a CALL or INT until control returns to the next instruction
+    /// with the same stack pointer and mode.
+    /// Any other instruction is stepped normally.
+    /// Returns the number of instructions executed.
+    pub fn step_over(&mut self) -> usize {
+        if !(self.next_opcode() == "CALL" || self.next_opcode() == "INT") {
+            return self.step(1);
+        }
+        let regs = &self.state().regs;
+        let (ip, sp) = match (regs.ip.as_addr(), regs.sp.as_addr()) {
+            (Some(ip), Some(sp)) => (ip, sp),
+            _ => return self.step(1),
+        };
+        let is_user = self.is_user_mode();
+        self.step_until("Step over", |xsm| {
+            xsm.is_user_mode() == is_user
+                && xsm.state().regs.ip.as_addr() == Some(ip + 2)
+                && xsm.state().regs.sp.as_addr() == Some(sp)
+        })
+    }
+
+    /// Runs until the current routine returns to its caller:
+    /// a RET that leaves SP below its current value,
+    /// or an IRET back to user mode.
+    /// Returns the number of instructions executed.
+    pub fn step_out(&mut self) -> usize {
+        let sp = match self.state().regs.sp.as_int() {
+            Some(sp) => sp,
+            None => return 0,
+        };
+        let is_user = self.is_user_mode();
+        let mut executing = self.next_opcode().to_owned();
+        self.step_until("Step out", |xsm| {
+            let returned = match executing.as_str() {
+                "RET" => {
+                    xsm.is_user_mode() == is_user
+                        && xsm
+                            .state()
+                            .regs
+                            .sp
+                            .as_int()
+                            .is_some_and(|new_sp| new_sp < sp)
+                }
+                "IRET" => !is_user && xsm.is_user_mode(),
+                _ => false,
+            };
+            executing = xsm.next_opcode().to_owned();
+            returned
+        })
+    }
+
+    /// Steps one instruction at a time until `done` returns true,
+    /// or the machine halts, reaches an exception,
+    /// or is stopped by a breakpoint or watchpoint.
+    fn step_until(&mut self, action: &'static str, mut done: impl FnMut(&XSM) -> bool) -> usize {
+        let mut stepped = 0;
+        loop {
+            let n = self.step(1);
+            stepped += n;
+            if n == 0
+                || self.running
+                || self.is_halted()
+                || self.state().is_exception_edge
+                || self.breakpoint_hit.is_some()
+                || self.watch_addr.is_some()
+                || done(self)
+            {
+                break;
+            }
+            if stepped >= STEP_UNTIL_LIMIT {
+                self.errors.push(XSMError::GaveUp { action, stepped });
+                break;
+            }
+        }
+        stepped
+    }
+
+    /// The opcode of the instruction at IP, as shown in the status.
+    pub fn next_opcode(&self) -> &str {
+        parse::opcode(&self.state().next_instr)
+    }
+
+    /// Runs the machine until a BRKP instruction, a watchpoint or HALT,
+    /// like xsm's `continue` command. Returns immediately;
+    /// call `poll` until the machine stops.
+    /// Breakpoints set in the debugger are not checked while running.
+    pub fn run(&mut self) {
+        if self.running || self.is_halted() {
+            return;
+        }
+        if self.is_tracing() {
+            self.errors.push(XSMError::RunWhileTracing);
+            return;
+        }
+        self.breakpoint_hit = None;
+        self.watchpoint_hits.clear();
+        self.watch_addr = None;
+        self.history.seek(None);
+        self._feed_queued();
+        if let Err(e) = self.backend.run() {
+            self._push_error(XSMError::Backend(e));
+            return;
+        }
+        self._record_state();
+        self.running = true;
+        self.interrupted = false;
+        // xsm does not report how many instructions it executed.
+        self.state.steps_exact = false;
+    }
+
+    /// Checks whether the machine started by `run` has stopped, without blocking.
+    /// Once it has, the state is reloaded as after a step.
+    /// A machine that stopped at an INI is given the next queued line
+    /// and run again. xsm itself does not stop there, so its queued input
+    /// is only written once it stops for another reason.
+    /// Returns true if the machine is not running.
+    pub fn poll(&mut self) -> bool {
+        if !self.running {
+            return true;
+        }
+        let stop = match self.backend.poll() {
+            Ok(Some(stop)) => stop,
+            Ok(None) => return false,
+            Err(e) => {
+                // Whatever stopped the machine, it is no longer running.
+                self._push_error(XSMError::Backend(e));
+                self.running = false;
+                return true;
+            }
+        };
+        self.running = false;
+        // Input written while running is read by whatever IN or INI
+        // the machine was at; the INI at IP, if any, is a new one.
+        self.ini_fed = false;
+        self.load_state(stop);
+        if !self.is_halted() && self.watch_addr.is_some() {
+            self._update_watchpoints();
+        }
+        if self._is_blocked_on_input()
+            && self.watch_addr.is_none()
+            && !self.interrupted
+            && self._feed_queued()
+        {
+            self.run();
+            return !self.running;
+        }
+        true
+    }
+
+    /// Stops the machine started by `run`, for a machine that would
+    /// otherwise never reach a breakpoint. `poll` returns true once it has.
+    pub fn interrupt(&mut self) {
+        if !self.running {
+            return;
+        }
+        self.interrupted = true;
+        if let Err(e) = self.backend.interrupt() {
+            self._push_error(XSMError::Backend(e));
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    /// False once the machine has been run with `continue`,
+    /// since the number of instructions it executed is unknown.
+    pub fn is_step_count_exact(&self) -> bool {
+        self.state().steps_exact
+    }
+
+    /// Large steps are split into chunks, between which progress is reported
+    /// and a cancelled operation stops. While a trace is recorded,
+    /// instructions are executed one at a time so that each gets a record.
+    /// Queued console input is written between chunks as the machine asks for it.
+    /// Returns the number of instructions executed, fewer than n
+    /// if the machine halted, the backend failed to step,
+    /// the operation was cancelled or an IN or INI has no input.
+    fn _step(&mut self, n: usize) -> usize {
+        let mut stepped = 0;
+        while stepped < n && !self.is_halted() && !self.progress.is_cancelled() {
+            self._feed_queued();
+            if self._is_blocked_on_input() {
+                break;
+            }
+            let done = if self.trace.is_none() {
+                self._step_raw((n - stepped).min(STEP_CHUNK))
+            } else if self._step_traced() {
+                1
+            } else {
+                0
+            };
+            if done == 0 {
+                break;
+            }
+            stepped += done;
+            self.progress.advance(done);
+        }
+        self._feed_queued();
+        stepped
+    }
+
+    fn _step_traced(&mut self) -> bool {
+        let step = self.state.step_count;
+        let is_user = self.is_user_mode();
+        let ip = self.state.regs.ip.as_addr().unwrap_or(0);
+        let instr = self.state.next_instr.clone();
+        let output_len = self.output.len();
+        if self._step_raw(1) == 0 {
+            return false;
+        }
+        let trace = match self.trace.as_mut() {
+            Some(trace) => trace,
+            None => return true,
         };
+        let result = trace.record(
+            step,
+            is_user,
+            ip,
+            &instr,
+            &self.state.regs,
+            &self.output[output_len..],
+        );
+        if let Err(e) = result {
+            self.errors.push(XSMError::Trace(e));
+            self.trace = None;
+        }
+        true
+    }
+
+    pub fn start_trace(&mut self, path: &Path) -> io::Result<()> {
+        self.trace = Some(TraceWriter::create(path)?);
+        Ok(())
+    }
+
+    pub fn stop_trace(&mut self) {
+        if let Some(mut trace) = self.trace.take() {
+            if let Err(e) = trace.flush() {
+                self.errors.push(XSMError::Trace(e));
+            }
+        }
+    }
+
+    pub fn is_tracing(&self) -> bool {
+        self.trace.is_some()
+    }
 
-        sleep(Duration::from_millis(200));
-        if xsm.xsm.try_wait().transpose().is_some() {
-            xsm.halted = true;
-            println!("Error: xsm exited without entering debug mode.");
-            println!("Please check your command line");
-            Err(())
+    /// The progress of the operation being run, which can be cancelled
+    /// from another thread.
+    pub fn get_progress(&self) -> Arc<Progress> {
+        self.progress.clone()
+    }
+
+    /// The state is recorded only once the backend has stepped,
+    /// so a failed step leaves the history as it was.
+    /// Returns the number of instructions executed, 0 if the step failed.
+    fn _step_raw(&mut self, n: usize) -> usize {
+        let start = self.state.step_count;
+        let stop = match self.backend.step(n) {
+            Ok(stop) => stop,
+            Err(e) => {
+                // A step that timed out may still complete,
+                // so the count and the cached memory are no longer known.
+                if let BackendError::Timeout { .. } = e {
+                    self.state.steps_exact = false;
+                    self.state.pages.clear();
+                }
+                self._push_error(XSMError::Backend(e));
+                return 0;
+            }
+        };
+        if stop.executed_unknown {
+            return self._step_to_end(start, n);
+        }
+        self._record_state();
+        self.state.step_count += stop.executed;
+        if stop.executed > 0 {
+            self.ini_fed = false;
+            if stop.console_read {
+                self.awaiting_input += 1;
+            }
+        }
+        let executed = stop.executed;
+        self.load_state(stop);
+        self._checkpoint();
+        executed
+    }
+
+    /// Finds where the machine came to an end within a step of n instructions
+    /// from `start`, which the backend did not tell: it is restarted and
+    /// replayed to `start`, then stepped one instruction at a time.
+    /// The history and the errors are kept, as the replay goes the same way.
+    /// Returns the number of instructions executed from `start`.
+    fn _step_to_end(&mut self, start: usize, n: usize) -> usize {
+        let history = std::mem::replace(&mut self.history, History::new(0));
+        let mut errors = std::mem::take(&mut self.errors);
+        let respawned = self._respawn();
+        if let Err(source) = respawned {
+            errors.push(XSMError::Restart {
+                command: self.command.clone(),
+                source,
+            });
         } else {
-            xsm.load_state();
-            Ok(xsm)
+            let queued = std::mem::take(&mut self.queued_input);
+            self._replay_to(start, 0);
+            self.queued_input = queued;
+        }
+        errors.append(&mut self.errors);
+        self.errors = errors;
+        self.history = history;
+        if self.state.step_count != start {
+            return 0;
+        }
+        let mut executed = 0;
+        while executed < n && !self.is_halted() && !self._is_blocked_on_input() {
+            match self._step_raw(1) {
+                0 => break,
+                done => executed += done,
+            }
         }
+        executed
     }
 
-    /// If not halted returns (self, program output)
-    pub fn step(&mut self, n: usize) {
-        writeln!(self.stdin, "step {}", n).expect("Failed to send command to xsm");
-        if let Ok(Some(retcode)) = self.xsm.try_wait() {
-            eprintln!("Halted {}", retcode);
-            self.halted = true;
-            self._read_status();
+    /// Keeps a checkpoint once CHECKPOINT_INTERVAL steps have passed
+    /// since the last one. Steps already covered, as in a replay, add none.
+    /// A replayed trace always runs the same, so it needs none.
+    fn _checkpoint(&mut self) {
+        if !self.state.steps_exact || self.is_halted() || self.is_trace_replay() {
             return;
         }
-        self.load_state();
+        let last = self
+            .checkpoints
+            .last()
+            .map_or(0, |checkpoint| checkpoint.step);
+        if self.state.step_count >= last + CHECKPOINT_INTERVAL {
+            self.checkpoints.push(Checkpoint {
+                step: self.state.step_count,
+                inputs: self.inputs.len(),
+                regs: self.state.regs.clone(),
+            });
+        }
     }
 
+    /// True once the machine has come to an end, however it did.
     pub fn is_halted(&self) -> bool {
-        self.halted
+        self.end.is_some()
+    }
+
+    /// How the machine came to an end, if it has.
+    pub fn get_end(&self) -> Option<End> {
+        self.end
     }
 
     pub fn is_next_halt(&self) -> bool {
-        self.is_next_halt
+        self.state().is_next_halt
     }
 
     pub fn is_exception_edge(&self) -> bool {
-        self.is_exception_edge
+        self.state().is_exception_edge
+    }
+
+    pub fn get_step_count(&self) -> usize {
+        self.state().step_count
+    }
+
+    /// The state being viewed: a past one from the history, or the live one.
+    fn state(&self) -> &MachineState {
+        self.history.current().unwrap_or(&self.state)
+    }
+
+    /// Pushes the live state into the history before it changes.
+    /// Pages equal to those of the previous state are shared with it.
+    fn _record_state(&mut self) {
+        let mut state = self.state.clone();
+        state.output_len = self.output.len();
+        if let Some(prev) = self.history.last() {
+            for (page, data) in state.pages.iter_mut() {
+                if let Some(prev_data) = prev.pages.get(page) {
+                    if prev_data == data {
+                        *data = prev_data.clone();
+                    }
+                }
+            }
+        }
+        self.history.push(state);
+        self.state.pages.clear();
+    }
+
+    /// True while a past state is shown instead of the live one.
+    pub fn is_viewing_history(&self) -> bool {
+        self.history.pos().is_some()
+    }
+
+    pub fn get_history_len(&self) -> usize {
+        self.history.len()
+    }
+
+    /// Index into the history of the state being viewed;
+    /// equal to the history length for the live state.
+    pub fn get_history_pos(&self) -> usize {
+        self.history.pos().unwrap_or_else(|| self.history.len())
+    }
+
+    pub fn set_history_pos(&mut self, pos: usize) {
+        self.history.seek(Some(pos));
+    }
+
+    /// Shows the state one instruction before the one being viewed.
+    /// The history has a state per step sent to the backend, which may be
+    /// many instructions apart; if the one before is not there, xsm is
+    /// replayed to it as by `goto_step`. While the step count is not exact
+    /// or a trace is recorded, the previous state in the history is shown.
+    /// Returns false if there is no older state.
+    pub fn step_back(&mut self) -> bool {
+        let state = self.state();
+        if !state.steps_exact || state.step_count == 0 || self.is_tracing() {
+            return self.history.back();
+        }
+        let target = state.step_count - 1;
+        self._goto_step(target, STEP_BACK_RECORDED);
+        self.state().step_count == target
+    }
+
+    pub fn go_live(&mut self) {
+        self.history.seek(None);
+    }
+
+    pub fn get_history_capacity(&self) -> usize {
+        self.history.capacity()
+    }
+
+    pub fn set_history_capacity(&mut self, capacity: usize) {
+        self.history.set_capacity(capacity);
+    }
+
+    pub fn get_breakpoints(&self) -> &Vec<Breakpoint> {
+        &self.breakpoints
+    }
+
+    /// Returns the breakpoint that stopped the last step, if any.
+    pub fn get_breakpoint_hit(&self) -> Option<&Breakpoint> {
+        self.breakpoint_hit.map(|i| &self.breakpoints[i])
+    }
+
+    /// Returns the index of the new breakpoint,
+    /// or of the existing one at the same address.
+    pub fn add_breakpoint(&mut self, addr: usize, is_virtual: bool) -> usize {
+        if let Some(i) = self.find_breakpoint(addr, is_virtual) {
+            return i;
+        }
+        self.breakpoints.push(Breakpoint {
+            addr,
+            is_virtual,
+            enabled: true,
+            hits: 0,
+            condition: None,
+            ignore_count: 0,
+        });
+        self.breakpoints.len() - 1
+    }
+
+    pub fn remove_breakpoint(&mut self, index: usize) {
+        if index < self.breakpoints.len() {
+            self.breakpoints.remove(index);
+            self.breakpoint_hit = None;
+        }
+    }
+
+    pub fn set_breakpoint_enabled(&mut self, index: usize, enabled: bool) {
+        if let Some(bp) = self.breakpoints.get_mut(index) {
+            bp.enabled = enabled;
+        }
+    }
+
+    /// Adds a breakpoint, or updates the one at the same address,
+    /// with its condition and ignore count. The condition is parsed first,
+    /// so a malformed one leaves the breakpoints as they were.
+    /// Returns the index of the breakpoint.
+    pub fn set_breakpoint(
+        &mut self,
+        addr: usize,
+        is_virtual: bool,
+        condition: &str,
+        ignore_count: usize,
+    ) -> Result<usize, ExprError> {
+        let condition = parse_condition(condition)?;
+        let index = self.add_breakpoint(addr, is_virtual);
+        let bp = &mut self.breakpoints[index];
+        bp.condition = condition;
+        bp.ignore_count = ignore_count;
+        Ok(index)
+    }
+
+    /// An empty condition removes the existing one.
+    pub fn set_breakpoint_condition(
+        &mut self,
+        index: usize,
+        condition: &str,
+    ) -> Result<(), ExprError> {
+        let condition = parse_condition(condition)?;
+        if let Some(bp) = self.breakpoints.get_mut(index) {
+            bp.condition = condition;
+        }
+        Ok(())
+    }
+
+    pub fn set_breakpoint_ignore_count(&mut self, index: usize, ignore_count: usize) {
+        if let Some(bp) = self.breakpoints.get_mut(index) {
+            bp.ignore_count = ignore_count;
+        }
+    }
+
+    pub fn find_breakpoint(&self, addr: usize, is_virtual: bool) -> Option<usize> {
+        self.breakpoints
+            .iter()
+            .position(|bp| bp.addr == addr && bp.is_virtual == is_virtual)
+    }
+
+    /// Adds a breakpoint at addr, or removes it if one already exists.
+    pub fn toggle_breakpoint(&mut self, addr: usize, is_virtual: bool) {
+        match self.find_breakpoint(addr, is_virtual) {
+            Some(i) => self.remove_breakpoint(i),
+            None => {
+                self.add_breakpoint(addr, is_virtual);
+            }
+        }
+    }
+
+    pub fn get_watchpoints(&self) -> &Vec<Watchpoint> {
+        &self.watchpoints
+    }
+
+    /// True if the last step was stopped by a watchpoint.
+    pub fn is_watch_triggered(&self) -> bool {
+        self.watch_addr.is_some()
+    }
+
+    /// Watchpoints written to by the last step: the one whose write
+    /// stopped it, even if the value stayed the same, and any that changed.
+    pub fn get_watchpoint_hits(&self) -> impl Iterator<Item = &Watchpoint> {
+        self.watchpoint_hits
+            .iter()
+            .map(move |&i| &self.watchpoints[i])
+    }
+
+    pub fn add_watchpoint(&mut self, addr: usize) {
+        if self.running || self.watchpoints.iter().any(|wp| wp.addr == addr) {
+            return;
+        }
+        if !self.backend.has_memory() {
+            self.errors.push(XSMError::NoMemory);
+            return;
+        }
+        if let Err(e) = self.backend.watch(addr) {
+            self._push_error(XSMError::Backend(e));
+            return;
+        }
+        let value = self._read_word_live(addr);
+        self.watchpoints.push(Watchpoint {
+            addr,
+            old_value: value.clone(),
+            value,
+            hits: 0,
+        });
+    }
+
+    /// xsm can only clear all watchpoints at once,
+    /// so the remaining ones are set again.
+    pub fn remove_watchpoint(&mut self, index: usize) {
+        if self.running || index >= self.watchpoints.len() {
+            return;
+        }
+        self.watchpoints.remove(index);
+        self.watchpoint_hits.clear();
+        if let Err(e) = self._rewatch() {
+            self._push_error(XSMError::Backend(e));
+        }
+    }
+
+    fn _rewatch(&mut self) -> Result<(), BackendError> {
+        self.backend.watch_clear()?;
+        for wp in &self.watchpoints {
+            self.backend.watch(wp.addr)?;
+        }
+        Ok(())
+    }
+
+    pub fn clear_watchpoints(&mut self) {
+        if self.running {
+            return;
+        }
+        self.watchpoints.clear();
+        self.watchpoint_hits.clear();
+        if let Err(e) = self.backend.watch_clear() {
+            self._push_error(XSMError::Backend(e));
+        }
+    }
+
+    /// Rereads every watched address and records the ones that changed,
+    /// and the one the machine reported written to.
+    fn _update_watchpoints(&mut self) {
+        for i in 0..self.watchpoints.len() {
+            let addr = self.watchpoints[i].addr;
+            let value = self._read_word_live(addr);
+            let wp = &mut self.watchpoints[i];
+            if wp.value != value || self.watch_addr == Some(addr) {
+                wp.old_value = std::mem::replace(&mut wp.value, value);
+                wp.hits += 1;
+                self.watchpoint_hits.push(i);
+            }
+        }
+    }
+
+    pub fn is_user_mode(&self) -> bool {
+        matches!(self.state().mode, Mode::User)
+    }
+
+    /// Marks the first enabled breakpoint at the current IP
+    /// whose condition holds as hit.
+    /// Returns true if the machine should stop there.
+    fn _check_breakpoints(&mut self) -> bool {
+        let ip = match self.state.regs.ip.as_addr() {
+            Some(ip) => ip,
+            None => return false,
+        };
+        let is_user = self.is_user_mode();
+        let phy_ip = if is_user {
+            self._page_vir_to_phy(ip / XSM_PAGE_LEN)
+                .ok()
+                .map(|page| page * XSM_PAGE_LEN + ip % XSM_PAGE_LEN)
+        } else {
+            Some(ip)
+        };
+        let candidates: Vec<usize> = (0..self.breakpoints.len())
+            .filter(|&i| {
+                let bp = &self.breakpoints[i];
+                bp.enabled
+                    && if bp.is_virtual {
+                        is_user && bp.addr == ip
+                    } else {
+                        phy_ip == Some(bp.addr)
+                    }
+            })
+            .collect();
+        for i in candidates {
+            if let Some(condition) = self.breakpoints[i].condition.clone() {
+                match condition.eval(self) {
+                    Ok(value) if !value.is_true() => continue,
+                    Ok(_) => {}
+                    Err(e) => {
+                        // Stop so that the condition can be fixed.
+                        self.errors.push(XSMError::Condition {
+                            condition: condition.source().to_owned(),
+                            error: e,
+                        });
+                        self.breakpoint_hit = Some(i);
+                        return true;
+                    }
+                }
+            }
+            let bp = &mut self.breakpoints[i];
+            bp.hits += 1;
+            if bp.ignore_count > 0 {
+                bp.ignore_count -= 1;
+                continue;
+            }
+            self.breakpoint_hit = Some(i);
+            return true;
+        }
+        false
     }
 
     // Returns (base_addr, ip, code)
     pub fn get_code(&mut self, max_lines: usize) -> (usize, usize, Vec<String>) {
-        let ip: usize = match self.regs.ip.parse() {
-            Ok(ip) => ip,
-            Err(_) => {
-                self.errors.push("IP is not an unsigned number".to_owned());
+        if self.running {
+            return self.last_code.clone();
+        }
+        let ip = match self.state().regs.ip.as_addr() {
+            Some(ip) => ip,
+            None => {
+                let ip = self.state().regs.ip.clone();
+                self._push_code_error(XSMError::IPInvalid(ip));
                 return self.last_code.clone();
             }
         };
 
+        if !self.backend.has_memory() {
+            let is_user = self.is_user_mode();
+            let start = ip.saturating_sub(max_lines / 2 * 2);
+            let code = (0..max_lines)
+                .map(|i| {
+                    self.backend
+                        .instr_at(is_user, start + 2 * i)
+                        .unwrap_or("")
+                        .to_owned()
+                })
+                .collect();
+            self.last_code = (start, ip, code);
+            self.code_error = None;
+            return self.last_code.clone();
+        }
+
         let max_addr = max_lines * 2;
         let start;
-        let code = if let Mode::User = self.mode {
-            let max_range = match Self::get_valid_mem_range(ip, &self.page_table) {
+        let code = if self.is_user_mode() {
+            let max_range = match Self::get_valid_mem_range(ip, &self.state().page_table) {
                 Ok(r) => r,
                 Err((ip, page)) => {
-                    self.errors.push(format!(
-                        "IP: {}, Page: {} not found in page table",
-                        ip, page
-                    ));
+                    self._push_code_error(XSMError::IPNotPaged { ip, page });
                     return self.last_code.clone();
                 }
             };
@@ -211,181 +1481,143 @@ impl XSM {
             let end = end_ - (end_ % 2);
             self.read_mem_range(start, end)
         };
-        let code = code.chunks_exact(2).map(|c| c[0].clone() + &c[1]).collect();
+        let code = code
+            .chunks_exact(2)
+            .map(|c| format!("{}{}", c[0], c[1]))
+            .collect();
+
+        self.last_code = (start, ip, code);
+        self.code_error = None;
+        self.last_code.clone()
+    }
 
-        (start, ip, code)
+    fn _push_code_error(&mut self, error: XSMError) {
+        let text = error.to_string();
+        if self.code_error.as_ref() != Some(&text) {
+            self.code_error = Some(text);
+            self.errors.push(error);
+        }
     }
 
     pub fn get_regs(&self) -> &XSMRegs {
-        &self.regs
+        &self.state().regs
     }
 
     pub fn get_page_table(&self) -> &Vec<XSMPageTableEntry> {
-        &self.page_table
+        &self.state().page_table
     }
 
-    pub fn get_errors(&self) -> &Vec<String> {
+    pub fn get_errors(&self) -> &[XSMError] {
         &self.errors
     }
 
-    pub fn get_output(&self) -> &Vec<String> {
-        &self.output
+    pub fn dismiss_error(&mut self, index: usize) {
+        if index < self.errors.len() {
+            self.errors.remove(index);
+        }
+    }
+
+    pub fn clear_errors(&mut self) {
+        self.errors.clear();
+    }
+
+    pub fn get_output(&self) -> &[String] {
+        match self.history.current() {
+            Some(state) => &self.output[..state.output_len],
+            None => &self.output,
+        }
     }
 
     pub fn get_status(&self) -> &str {
-        &self.status
+        &self.state().status
     }
 
-    fn get_stdout(&mut self, lines: usize) -> Vec<String> {
-        let mut vec = Vec::with_capacity(lines);
-        if lines == 0 {
-            while let Ok(s) = self.stdout.recv_timeout(Duration::from_millis(10)) {
-                vec.push(s);
+    /// Reports an error to the user. A backend error saying that xsm
+    /// has exited also ends the machine, whichever command found it out.
+    fn _push_error(&mut self, error: XSMError) {
+        if let XSMError::Backend(BackendError::Exited(status))
+        | XSMError::ReadRegs(BackendError::Exited(status))
+        | XSMError::ReadMemPage {
+            source: BackendError::Exited(status),
+            ..
+        } = &error
+        {
+            if self.end.is_none() {
+                self.end = Some(End::of_exit(*status));
             }
-        } else {
-            for _ in 0..lines {
-                vec.push(
-                    self.stdout
-                        .recv_timeout(Duration::from_millis(1000))
-                        .map_err(|e| {
-                            eprintln!("Read so far");
-                            dbg!(&vec);
-                        })
-                        .unwrap(),
-                );
-            }
-        }
-        vec
-    }
-
-    /// Must be called right after entering debug mode
-    /// or right after sending step command
-    /// Returns: Mode, Program output if there was an out instruction
-    fn load_state(&mut self) {
-        self._read_status();
-        if self.is_halted() {
-            return;
         }
-        self._read_regs();
-        self._read_page_table();
+        self.errors.push(error);
     }
 
-    /// ------------ Called by load state --------------- ///
-    fn _read_status(&mut self) {
-        let mut lines = self.get_stdout(0);
-        if lines.len() < 3 {
-            dbg!(lines);
-            return;
+    /// Updates the live state from a stop of the backend.
+    fn load_state(&mut self, stop: Stop) {
+        if let Some(error) = stop.error {
+            self._push_error(XSMError::Backend(error));
         }
-        if lines[0]
-            .trim_start_matches("debug> ")
-            .starts_with("Machine is halting.")
-        {
-            self.halted = true;
+        self.output.extend(stop.output);
+        self.inputs.extend(stop.input);
+        if stop.watch_addr.is_some() {
+            self.watch_addr = stop.watch_addr;
+        }
+        if let Some(end) = stop.end {
+            self.end = Some(end);
             return;
         }
-        lines[0] = lines[0].trim_start_matches("debug> ").to_owned();
-
-        self.status.clear();
-        for line in lines.iter().skip(lines.len() - 3) {
-            self.status.push_str(line);
+        if let Some(status) = stop.status {
+            self.state.status = status.text;
+            self.state.is_next_halt = status.next_instr.starts_with("HALT");
+            self.state.next_instr = status.next_instr;
+            self.state.is_exception_edge = status.ip == 1024;
+            self.state.mode = status.mode;
         }
-
-        for line in lines.iter().take(lines.len() - 3) {
-            self.output.push(line.clone());
-        }
-
-        let next_instr_line = lines.last().unwrap();
-        self.is_next_halt = next_instr_line
-            .split(": ")
-            .last()
-            .unwrap()
-            .starts_with("HALT");
-
-        let next_ip_str: &str = next_instr_line
-            .split("IP = ")
-            .last()
-            .unwrap()
-            .split(",")
-            .next()
-            .unwrap();
-        let next_ip: u32 = next_ip_str.parse().unwrap();
-        self.is_exception_edge = next_ip == 1024;
-
-        let mode_line = &lines[lines.len() - 2];
-        let mode_char = mode_line.chars().nth(6).unwrap();
-        self.mode = match mode_char {
-            'K' => Mode::Kernel,
-            'U' => Mode::User,
-            _ => panic!("Unexpected mode: '{}'\nLines read: {:#?}", mode_char, lines),
-        };
-    }
-
-    fn _read_regs(&mut self) {
-        writeln!(self.stdin, "reg").expect("Failed to send command to xsm");
-        let lines = self.get_stdout(7);
-
-        fn ref_table(i: usize, regs: &mut XSMRegs) -> &mut String {
-            if i < 20 {
-                &mut regs.r[i]
-            } else if (i - 20) < 4 {
-                &mut regs.p[i - 20]
-            } else {
-                [
-                    &mut regs.bp,
-                    &mut regs.sp,
-                    &mut regs.ip,
-                    &mut regs.ptbr,
-                    &mut regs.ptlr,
-                    &mut regs.eip,
-                    &mut regs.ec,
-                    &mut regs.epn,
-                    &mut regs.ema,
-                ][i - 20 - 4]
-            }
-        }
-
-        let mut i = 0;
-        for line in lines {
-            for word in line.split('\t') {
-                if word == "\n" {
-                    continue;
-                }
-                let val = word.split(": ").nth(1).unwrap();
-                let reg = ref_table(i, &mut self.regs);
-                reg.clear();
-                reg.push_str(val);
-                i += 1;
+        match self.backend.read_regs() {
+            Ok(regs) => self.state.regs = regs,
+            Err(e) => {
+                self._push_error(XSMError::ReadRegs(e));
+                return;
             }
         }
+        if self.backend.has_memory() {
+            self._read_page_table();
+        }
     }
 
+    // ------------ Called by load state --------------- //
     fn _read_page_table(&mut self) {
-        self.page_table.clear();
-        let ptbr: usize = if let Ok(ptbr) = self.regs.ptbr.parse() {
+        self.state.page_table.clear();
+        let ptbr = if let Some(ptbr) = self.state.regs.ptbr.as_addr() {
             ptbr
         } else {
-            self.errors
-                .push(format!("PTBR: '{}' is invalid", self.regs.ptbr));
+            let ptbr = self.state.regs.ptbr.clone();
+            self._push_page_table_error(XSMError::PTBRInvalid(ptbr));
             return;
         };
-        let ptlr: usize = if let Ok(ptlr) = self.regs.ptlr.parse() {
+        let ptlr = if let Some(ptlr) = self.state.regs.ptlr.as_addr() {
             ptlr
         } else {
-            self.errors
-                .push(format!("PTLR: '{}' is invalid", self.regs.ptlr));
+            let ptlr = self.state.regs.ptlr.clone();
+            self._push_page_table_error(XSMError::PTLRInvalid(ptlr));
             return;
         };
+        self.page_table_error = None;
         let page_table_str = self.read_mem_range(ptbr, ptbr + ptlr * 2);
         for entry_mem in page_table_str.chunks_exact(2) {
             let entry = XSMPageTableEntry {
                 phy: entry_mem[0].clone(),
                 aux: entry_mem[1].clone(),
             };
-            self.page_table.push(entry);
+            self.state.page_table.push(entry);
         }
     }
-    /// ------------ End of called by load state --------------- ///
+
+    fn _push_page_table_error(&mut self, error: XSMError) {
+        let text = error.to_string();
+        if self.page_table_error.as_ref() != Some(&text) {
+            self.page_table_error = Some(text);
+            self.errors.push(error);
+        }
+    }
+    // ------------ End of called by load state --------------- //
 
     fn _pageify(start_addr: usize, end_addr: usize) -> (usize, usize, usize, usize) {
         let start_page = start_addr / XSM_PAGE_LEN;
@@ -395,51 +1627,124 @@ impl XSM {
         (start_page, end_page, start_page_skip, end_page_take)
     }
 
-    fn _page_vir_to_phy(&self, vir_page: usize) -> Result<usize, XSMInternalError> {
-        if vir_page > self.page_table.len() {
-            return Err(XSMInternalError::VirtualMemoryOutOfBounds {
+    fn _page_vir_to_phy(&self, vir_page: usize) -> Result<usize, XSMError> {
+        let page_table = &self.state().page_table;
+        if vir_page >= page_table.len() {
+            return Err(XSMError::VirtualMemoryOutOfBounds {
                 addr: vir_page * XSM_PAGE_LEN,
             });
         }
-        let page_table_entry = &self.page_table[vir_page];
-        match page_table_entry.phy.parse::<isize>() {
-            Err(_) => Err(XSMInternalError::InvalidPageTableEntry {
+        let page_table_entry = &page_table[vir_page];
+        match page_table_entry.phy.as_int() {
+            Some(-1) => Err(XSMError::VirtualMemoryNotPaged {
+                page: vir_page,
+                entry: page_table_entry.clone(),
+            }),
+            Some(i) if i >= 0 => Ok(i as usize),
+            _ => Err(XSMError::InvalidPageTableEntry {
                 index: vir_page,
                 entry: page_table_entry.clone(),
             }),
-            Ok(i) => {
-                if i == -1 {
-                    Err(XSMInternalError::VirtualMemoryNotPaged {
-                        page: vir_page,
-                        entry: page_table_entry.clone(),
-                    })
-                } else if i < -1 {
-                    Err(XSMInternalError::InvalidPageTableEntry {
-                        index: vir_page,
-                        entry: page_table_entry.clone(),
-                    })
-                } else {
-                    Ok(i as usize)
+        }
+    }
+
+    /// Returns nothing while the machine is running,
+    /// since xsm only reads commands in debug mode.
+    /// While viewing a past state, pages not read in that state
+    /// are rebuilt by a replay, and kept with the state.
+    fn read_mem_page(&mut self, page: usize) -> Vec<XSMWord> {
+        let (step, steps_exact) = match self.history.current() {
+            Some(state) => match state.pages.get(&page) {
+                Some(data) => return data.to_vec(),
+                None => (state.step_count, state.steps_exact),
+            },
+            None => return self._read_mem_page_live(page),
+        };
+        let data = match self._read_past_page(step, steps_exact, page) {
+            Some(data) => data,
+            None => return vec![XSMWord::Str("(not recorded)".to_owned()); XSM_PAGE_LEN],
+        };
+        if let Some(state) = self.history.current_mut() {
+            state.pages.insert(page, Arc::new(data.clone()));
+        }
+        data
+    }
+
+    /// Reads a page as it was after `step` instructions, from a second
+    /// machine replayed to it with the console input written so far.
+    /// The machine is kept, and stepped on for later states.
+    /// None if the step count is not exact or the backend has no memory.
+    fn _read_past_page(
+        &mut self,
+        step: usize,
+        steps_exact: bool,
+        page: usize,
+    ) -> Option<Vec<XSMWord>> {
+        if !steps_exact || self.running || !self.backend.has_memory() {
+            return None;
+        }
+        let mut past = match self.past.take() {
+            Some(past) if past.state.step_count <= step => past,
+            _ => match self.backend.restart() {
+                Ok(backend) => {
+                    let mut past = XSM::with_backend(&self.command, backend);
+                    past.history.set_capacity(0);
+                    Box::new(past)
                 }
-            }
+                Err(source) => {
+                    self.errors.push(XSMError::Restart {
+                        command: self.command.clone(),
+                        source,
+                    });
+                    return None;
+                }
+            },
+        };
+        past.inputs = self.inputs.clone();
+        past.checkpoints = self.checkpoints.clone();
+        past._replay_to(step, 0);
+        let data = if past.state.step_count == step {
+            Some(past._read_mem_page_live(page))
+        } else {
+            None
+        };
+        if past.errors.is_empty() {
+            self.past = Some(past);
+        } else {
+            self.errors.append(&mut past.errors);
         }
+        data
     }
 
-    fn read_mem_page(&mut self, page: usize) -> Vec<String> {
-        writeln!(self.stdin, "mem {}", page).expect("Failed to send command to xsm");
-        let _buf = self.get_stdout(1);
-        let mem: String = fs::read_to_string("mem").expect("Failed to read mem file.");
-        mem.lines()
-            .map(|l| {
-                let mut s = l.split(": ");
-                let _line_num = s.next();
-                let content = s.next().unwrap();
-                String::from(content)
-            })
-            .collect()
+    /// Reads a word from the live machine, even while viewing the history.
+    fn _read_word_live(&mut self, addr: usize) -> XSMWord {
+        self._read_mem_page_live(addr / XSM_PAGE_LEN)
+            .into_iter()
+            .nth(addr % XSM_PAGE_LEN)
+            .unwrap_or_default()
     }
 
-    pub fn read_mem_range(&mut self, start_addr: usize, end_addr: usize) -> Vec<String> {
+    /// Pages already read in the live state are taken from the cache,
+    /// so that windows redrawn every frame do not ask the backend again.
+    fn _read_mem_page_live(&mut self, page: usize) -> Vec<XSMWord> {
+        if self.running || !self.backend.has_memory() {
+            return Vec::new();
+        }
+        if let Some(data) = self.state.pages.get(&page) {
+            return data.to_vec();
+        }
+        let data = match self.backend.read_mem_page(page) {
+            Ok(data) => data,
+            Err(source) => {
+                self._push_error(XSMError::ReadMemPage { page, source });
+                return Vec::new();
+            }
+        };
+        self.state.pages.insert(page, Arc::new(data.clone()));
+        data
+    }
+
+    pub fn read_mem_range(&mut self, start_addr: usize, end_addr: usize) -> Vec<XSMWord> {
         let mut data = Vec::new();
         let (start_page, end_page, start_page_skip, end_page_take) =
             Self::_pageify(start_addr, end_addr);
@@ -457,14 +1762,14 @@ impl XSM {
                     .skip(start_page_skip),
             );
             for i in start_page + 1..end_page {
-                data.extend(self.read_mem_page(i).into_iter());
+                data.extend(self.read_mem_page(i));
             }
             data.extend(self.read_mem_page(end_page).into_iter().take(end_page_take));
         }
         data
     }
 
-    pub fn read_mem_range_vir(&mut self, start_addr: usize, end_addr: usize) -> Vec<String> {
+    pub fn read_mem_range_vir(&mut self, start_addr: usize, end_addr: usize) -> Vec<XSMWord> {
         let mut data = Vec::new();
         let (start_page_vir, end_page_vir, start_page_skip, end_page_take) =
             Self::_pageify(start_addr, end_addr);
@@ -484,7 +1789,7 @@ impl XSM {
             );
             for page_vir in start_page_vir + 1..end_page_vir {
                 let page_phy = try_or!(self._page_vir_to_phy(page_vir), Vec::new());
-                data.extend(self.read_mem_page(page_phy).into_iter());
+                data.extend(self.read_mem_page(page_phy));
             }
             let end_page_phy = try_or!(self._page_vir_to_phy(end_page_vir), Vec::new());
             data.extend(
@@ -499,7 +1804,7 @@ impl XSM {
     /// Return Ok(start_range, end_range) or Err(IP, Page)
     fn get_valid_mem_range(
         include_addr: usize,
-        page_table: &Vec<XSMPageTableEntry>,
+        page_table: &[XSMPageTableEntry],
     ) -> Result<(usize, usize), (usize, usize)> {
         let page = include_addr / XSM_PAGE_LEN;
         let pt_entry: &XSMPageTableEntry = match page_table.get(page) {
@@ -508,18 +1813,15 @@ impl XSM {
                 return Err((include_addr, page));
             }
         };
-        let _phy: usize = match pt_entry.phy.parse().ok() {
-            Some(e) => e,
-            None => {
-                return Err((include_addr, page));
-            }
-        };
+        if pt_entry.phy.as_addr().is_none() {
+            return Err((include_addr, page));
+        }
 
         let mut preceding_page = page;
         while preceding_page > 0 {
             let page_ = preceding_page - 1;
             let pt_entry = &page_table[page_];
-            if pt_entry.phy.parse::<usize>().is_ok() {
+            if pt_entry.phy.as_addr().is_some() {
                 preceding_page = page_;
             } else {
                 break;
@@ -530,7 +1832,7 @@ impl XSM {
         while succeeding_page < page_table.len() - 1 {
             let page_ = succeeding_page + 1;
             let pt_entry = &page_table[page_];
-            if pt_entry.phy.parse::<usize>().is_ok() {
+            if pt_entry.phy.as_addr().is_some() {
                 succeeding_page = page_;
             } else {
                 break;
@@ -543,3 +1845,30 @@ impl XSM {
         ))
     }
 }
+
+impl expr::Context for XSM {
+    fn regs(&self) -> &XSMRegs {
+        &self.state().regs
+    }
+
+    fn is_user_mode(&self) -> bool {
+        XSM::is_user_mode(self)
+    }
+
+    fn read_word(&mut self, addr: usize, is_virtual: bool) -> Option<XSMWord> {
+        let words = if is_virtual {
+            self.read_mem_range_vir(addr, addr + 1)
+        } else {
+            self.read_mem_range(addr, addr + 1)
+        };
+        words.into_iter().next()
+    }
+}
+
+/// A breakpoint condition, or None if it is empty.
+fn parse_condition(condition: &str) -> Result<Option<Expr>, ExprError> {
+    if condition.trim().is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(Expr::parse(condition)?))
+}
@@ -0,0 +1,67 @@
+//! Drives `XSM` against the built-in emulator, with programs written
+//! to block 0 of a disk image of their own.
+
+use std::path::{Path, PathBuf};
+
+use xdb::emulator::Config;
+use xdb::word::XSMWord;
+use xdb::xsm::{XSMError, XSM};
+
+/// Writes a disk.xfs whose block 0 holds `program`, one instruction
+/// per two words of 16 bytes.
+fn write_disk(path: &Path, program: &[&str]) {
+    let mut bytes = vec![0; 2 * 16 * program.len()];
+    for (instr, chunk) in program.iter().zip(bytes.chunks_mut(32)) {
+        chunk[..instr.len()].copy_from_slice(instr.as_bytes());
+    }
+    std::fs::write(path, bytes).unwrap();
+}
+
+fn emulate(name: &str, program: &[&str]) -> (XSM, PathBuf) {
+    let dir = std::env::temp_dir().join(format!("xdb-emulate-{}-{}", std::process::id(), name));
+    std::fs::create_dir_all(&dir).unwrap();
+    let disk_file = dir.join("disk.xfs");
+    write_disk(&disk_file, program);
+    let config = Config {
+        disk_file: disk_file.clone(),
+        ..Config::default()
+    };
+    (XSM::emulate("xsm", config).unwrap(), disk_file)
+}
+
+#[test]
+fn goto_replays_a_long_run() {
+    let (mut xsm, _) = emulate("goto", &["INR R0", "JMP 512"]);
+    assert_eq!(xsm.step(25_000), 25_000);
+    // Past the history, so the machine is restarted and replayed.
+    xsm.goto_step(20_002);
+    assert!(xsm.get_errors().is_empty());
+    assert_eq!(xsm.get_step_count(), 20_002);
+    assert_eq!(xsm.get_regs().r[0], XSMWord::Int(10_000));
+}
+
+#[test]
+fn goto_stops_where_the_replay_differs_from_the_run() {
+    let (mut xsm, disk_file) = emulate("diverge", &["INR R0", "JMP 512"]);
+    xsm.step(25_000);
+    write_disk(&disk_file, &["INR R1", "JMP 512"]);
+    xsm.goto_step(20_002);
+    assert!(matches!(
+        xsm.get_errors(),
+        [XSMError::ReplayDiverged { step: 10_000 }]
+    ));
+    assert_eq!(xsm.get_step_count(), 10_000);
+}
+
+#[test]
+fn step_back_goes_back_one_instruction_after_a_large_step() {
+    let (mut xsm, _) = emulate("step-back", &["INR R0", "JMP 512"]);
+    assert_eq!(xsm.step(2500), 2500);
+    assert!(xsm.step_back());
+    assert_eq!(xsm.get_step_count(), 2499);
+    assert!(xsm.step_back());
+    assert_eq!(xsm.get_step_count(), 2498);
+    assert!(xsm.is_viewing_history());
+    assert_eq!(xsm.get_regs().r[0], XSMWord::Int(1248));
+    assert!(xsm.get_errors().is_empty());
+}
@@ -0,0 +1,90 @@
+//! Launch configurations and the list of recent ones.
+
+use std::path::{Path, PathBuf};
+
+use xdb::launch::{self, LaunchConfig};
+use xdb::process::{SharedFiles, Timeouts};
+
+#[test]
+fn config_gives_the_xsm_command_line() {
+    let config = LaunchConfig {
+        xsm: "/opt/xsm/xsm".to_owned(),
+        disk_image: "/home/student/expos/disk.xfs".to_owned(),
+        timer: 100,
+        ..LaunchConfig::default()
+    };
+    assert_eq!(
+        config.argv(),
+        [
+            "/opt/xsm/xsm",
+            "--debug",
+            "--timer",
+            "100",
+            "--disk",
+            "20",
+            "--console",
+            "20"
+        ]
+    );
+}
+
+#[test]
+fn config_uses_its_disk_image_and_the_library_next_to_it() {
+    let config = LaunchConfig {
+        disk_image: "/home/student/expos/stage12.xfs".to_owned(),
+        ..LaunchConfig::default()
+    };
+    assert_eq!(
+        config.shared_files(),
+        SharedFiles {
+            disk: PathBuf::from("/home/student/expos/stage12.xfs"),
+            library: PathBuf::from("/home/student/expos/library.lib"),
+        }
+    );
+    assert_eq!(
+        LaunchConfig::default().shared_files(),
+        SharedFiles::in_dir(Path::new(""))
+    );
+}
+
+#[test]
+fn recent_configs_are_saved_most_recent_first() {
+    let path = std::env::temp_dir()
+        .join(format!("xdb-recent-{}", std::process::id()))
+        .join("recent.json");
+    assert!(launch::load_recent(&path).is_empty());
+
+    let first = LaunchConfig::default();
+    let second = LaunchConfig {
+        emulate: true,
+        ..LaunchConfig::default()
+    };
+    let mut recent = Vec::new();
+    launch::remember(&mut recent, &first);
+    launch::remember(&mut recent, &second);
+    launch::remember(&mut recent, &first);
+    assert_eq!(recent, [first.clone(), second.clone()]);
+
+    launch::save_recent(&path, &recent).unwrap();
+    assert_eq!(launch::load_recent(&path), [first, second]);
+    std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+}
+
+#[test]
+fn emulated_session_runs_the_chosen_disk_image() {
+    let dir = std::env::temp_dir().join(format!("xdb-launch-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let disk_image = dir.join("stage12.xfs");
+    let mut block = vec![0; 32];
+    block[..4].copy_from_slice(b"HALT");
+    std::fs::write(&disk_image, block).unwrap();
+    let config = LaunchConfig {
+        disk_image: disk_image.to_string_lossy().into_owned(),
+        emulate: true,
+        ..LaunchConfig::default()
+    };
+    let mut xsm = config.start(Timeouts::default()).unwrap();
+    assert_eq!(xsm.step(5), 3);
+    assert!(xsm.is_halted());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
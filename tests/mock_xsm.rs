@@ -0,0 +1,704 @@
+//! Drives `XSM` against mock_xsm, which speaks xsm's debug protocol
+//! and walks through the machine states given in a script.
+
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+use xdb::backend::{BackendError, End};
+use xdb::process::{self, Timeouts};
+use xdb::trace::TraceRecord;
+use xdb::word::XSMWord;
+use xdb::worker::{Command, Worker};
+use xdb::xsm::{XSMError, XSM};
+
+fn spawn(name: &str, script: Value) -> XSM {
+    spawn_with_timeouts(name, script, Timeouts::default())
+}
+
+fn spawn_with_timeouts(name: &str, script: Value, timeouts: Timeouts) -> XSM {
+    // A space in the path checks that the command line is not split on it.
+    let dir = std::env::temp_dir().join(format!("xdb mock-{}-{}", std::process::id(), name));
+    std::fs::create_dir_all(&dir).unwrap();
+    let script_path = dir.join("script.json");
+    std::fs::write(&script_path, script.to_string()).unwrap();
+    let argv = [
+        env!("CARGO_BIN_EXE_mock_xsm").to_owned(),
+        "--debug".to_owned(),
+        script_path.to_string_lossy().into_owned(),
+    ];
+    XSM::spawn_with_timeouts(&argv, timeouts).expect("Failed to spawn mock_xsm")
+}
+
+#[test]
+fn spawn_reads_initial_state() {
+    let xsm = spawn(
+        "spawn",
+        json!({ "states": [
+            { "mode": "K", "ip": 0, "instr": "MOV R0, 5", "regs": { "R0": "3", "SP": "100" } },
+            { "mode": "K", "ip": 2, "instr": "HALT" },
+        ] }),
+    );
+    assert!(!xsm.is_halted());
+    assert!(!xsm.is_user_mode());
+    assert_eq!(xsm.get_regs().r[0], XSMWord::Int(3));
+    assert_eq!(xsm.get_regs().sp, XSMWord::Int(100));
+    assert_eq!(xsm.get_regs().ip, XSMWord::Int(0));
+    assert!(xsm.get_status().contains("IP = 0"));
+    assert_eq!(xsm.next_opcode(), "MOV");
+    assert_eq!(xsm.get_step_count(), 0);
+}
+
+#[test]
+fn step_updates_registers_and_output() {
+    let mut xsm = spawn(
+        "step",
+        json!({ "states": [
+            { "mode": "K", "ip": 0, "instr": "MOV R0, 5" },
+            { "mode": "K", "ip": 2, "instr": "OUT", "regs": { "R0": "5" } },
+            { "mode": "K", "ip": 4, "instr": "HALT", "output": ["5"] },
+        ] }),
+    );
+    assert_eq!(xsm.step(1), 1);
+    assert_eq!(xsm.get_regs().r[0], XSMWord::Int(5));
+    assert_eq!(xsm.get_regs().ip, XSMWord::Int(2));
+    assert!(xsm.get_output().is_empty());
+    xsm.step(1);
+    assert_eq!(xsm.get_regs().ip, XSMWord::Int(4));
+    assert_eq!(xsm.get_output(), ["5"]);
+    assert!(xsm.is_next_halt());
+    assert_eq!(xsm.get_step_count(), 2);
+}
+
+#[test]
+fn step_past_the_end_halts() {
+    let mut xsm = spawn(
+        "halt",
+        json!({ "states": [
+            { "mode": "K", "ip": 0, "instr": "NOP" },
+            { "mode": "K", "ip": 2, "instr": "HALT" },
+        ] }),
+    );
+    xsm.step(1);
+    assert!(!xsm.is_halted());
+    xsm.step(1);
+    assert!(xsm.is_halted());
+    assert_eq!(xsm.get_end(), Some(End::Halted));
+}
+
+#[test]
+fn step_counts_only_instructions_before_the_halt() {
+    let mut xsm = spawn(
+        "halt-count",
+        json!({ "states": [
+            { "mode": "K", "ip": 0, "instr": "NOP" },
+            { "mode": "K", "ip": 2, "instr": "HALT" },
+        ] }),
+    );
+    assert_eq!(xsm.step(5), 2);
+    assert!(xsm.is_halted());
+    assert_eq!(xsm.get_step_count(), 2);
+}
+
+#[test]
+fn exit_without_halting_is_a_crash() {
+    let mut xsm = spawn(
+        "crash",
+        json!({ "states": [
+            { "mode": "K", "ip": 0, "instr": "NOP" },
+            { "mode": "K", "ip": 2, "instr": "NOP", "output": ["last words"], "exit": 3 },
+        ] }),
+    );
+    xsm.step(1);
+    assert!(xsm.is_halted());
+    match xsm.get_end() {
+        Some(End::Crashed(status)) => assert_eq!(status.code(), Some(3)),
+        end => panic!("Unexpected end: {:?}", end),
+    }
+    assert_eq!(xsm.get_output(), ["last words"]);
+    assert!(xsm.get_errors().is_empty());
+}
+
+#[test]
+fn killed_xsm_is_told_apart() {
+    let mut xsm = spawn(
+        "killed",
+        json!({ "states": [
+            { "mode": "K", "ip": 0, "instr": "NOP" },
+            { "mode": "K", "ip": 2, "instr": "NOP", "signal": 9 },
+        ] }),
+    );
+    xsm.step(1);
+    assert!(matches!(xsm.get_end(), Some(End::Killed(_))));
+}
+
+#[test]
+fn ini_waits_for_console_input() {
+    let mut xsm = spawn(
+        "ini",
+        json!({ "states": [
+            { "mode": "K", "ip": 0, "instr": "INI" },
+            { "mode": "K", "ip": 2, "instr": "OUT", "regs": { "P0": "hello" } },
+            { "mode": "K", "ip": 4, "instr": "HALT" },
+        ] }),
+    );
+    assert!(xsm.is_waiting_input());
+    assert_eq!(xsm.step(1), 0);
+    xsm.write_input("hello");
+    assert!(!xsm.is_waiting_input());
+    assert_eq!(xsm.step(1), 1);
+    assert_eq!(xsm.get_inputs(), [(0, "hello".to_owned())]);
+    assert!(xsm.get_errors().is_empty());
+}
+
+#[test]
+fn in_waits_until_its_line_is_written() {
+    let mut xsm = spawn(
+        "in",
+        json!({ "states": [
+            { "mode": "K", "ip": 0, "instr": "NOP" },
+            { "mode": "K", "ip": 2, "instr": "IN" },
+            { "mode": "K", "ip": 4, "instr": "NOP" },
+            { "mode": "K", "ip": 6, "instr": "HALT" },
+        ] }),
+    );
+    xsm.write_input("too early");
+    assert!(matches!(xsm.get_errors(), [XSMError::NotWaitingForInput]));
+    assert!(xsm.get_inputs().is_empty());
+    // The step ends right after the IN, before xsm reads its line.
+    assert_eq!(xsm.step(10), 2);
+    assert!(xsm.is_waiting_input());
+    assert_eq!(xsm.step(1), 0);
+    xsm.write_input("ls");
+    assert!(!xsm.is_waiting_input());
+    assert_eq!(xsm.step(1), 1);
+    assert_eq!(xsm.get_step_count(), 3);
+}
+
+#[test]
+fn in_ending_a_larger_step_waits_for_its_line() {
+    let mut xsm = spawn(
+        "in-chunk",
+        json!({ "states": [
+            { "mode": "K", "ip": 0, "instr": "NOP" },
+            { "mode": "K", "ip": 2, "instr": "NOP" },
+            { "mode": "K", "ip": 4, "instr": "IN" },
+            { "mode": "K", "ip": 6, "instr": "NOP" },
+            { "mode": "K", "ip": 8, "instr": "NOP" },
+        ] }),
+    );
+    assert_eq!(xsm.step(3), 3);
+    assert!(xsm.is_waiting_input());
+    xsm.write_input("ls");
+    assert_eq!(xsm.step(1), 1);
+    assert_eq!(xsm.get_inputs(), [(3, "ls".to_owned())]);
+}
+
+#[test]
+fn step_stops_at_ini_within_a_larger_step() {
+    let mut xsm = spawn(
+        "ini-chunk",
+        json!({ "states": [
+            { "mode": "K", "ip": 0, "instr": "NOP" },
+            { "mode": "K", "ip": 2, "instr": "NOP" },
+            { "mode": "K", "ip": 4, "instr": "INI" },
+            { "mode": "K", "ip": 6, "instr": "NOP" },
+            { "mode": "K", "ip": 8, "instr": "HALT" },
+        ] }),
+    );
+    assert_eq!(xsm.step(10), 2);
+    assert_eq!(xsm.get_regs().ip, XSMWord::Int(4));
+    assert!(xsm.is_waiting_input());
+    assert!(xsm.get_errors().is_empty());
+    xsm.write_input("hello");
+    assert_eq!(xsm.step(10), 3);
+    assert!(xsm.is_halted());
+    assert_eq!(xsm.get_inputs(), [(2, "hello".to_owned())]);
+}
+
+#[test]
+fn queued_input_is_written_when_asked_for_and_traced() {
+    let mut xsm = spawn(
+        "queue",
+        json!({ "states": [
+            { "mode": "K", "ip": 0, "instr": "INI" },
+            { "mode": "K", "ip": 2, "instr": "IN" },
+            { "mode": "K", "ip": 4, "instr": "NOP" },
+            { "mode": "K", "ip": 6, "instr": "HALT" },
+        ] }),
+    );
+    let trace_path = std::env::temp_dir().join(format!("xdb-queue-{}.jsonl", std::process::id()));
+    xsm.start_trace(&trace_path).unwrap();
+    xsm.queue_input("first");
+    xsm.queue_input("second");
+    assert_eq!(xsm.get_queued_input().len(), 1);
+    xsm.step(1);
+    xsm.step(1);
+    assert!(xsm.get_queued_input().is_empty());
+    assert!(!xsm.is_waiting_input());
+    assert_eq!(
+        xsm.get_inputs(),
+        [(0, "first".to_owned()), (2, "second".to_owned())]
+    );
+    xsm.step(1);
+    xsm.stop_trace();
+
+    let records: Vec<TraceRecord> = std::fs::read_to_string(&trace_path)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    std::fs::remove_file(&trace_path).unwrap();
+    let inputs: Vec<(usize, &[String])> = records
+        .iter()
+        .map(|record| (record.step, &record.input[..]))
+        .collect();
+    assert_eq!(
+        inputs,
+        [
+            (0, &["first".to_owned()][..]),
+            (1, &[][..]),
+            (2, &["second".to_owned()][..]),
+        ]
+    );
+}
+
+#[test]
+fn exception_is_reported() {
+    let mut xsm = spawn(
+        "exception",
+        json!({ "states": [
+            { "mode": "U", "ip": 0, "instr": "MOV R0, [5000]" },
+            {
+                "mode": "K", "ip": 1024, "instr": "MOV R0, EC",
+                "regs": { "EC": "2", "EIP": "0", "EMA": "5000" },
+            },
+        ] }),
+    );
+    assert!(!xsm.is_exception_edge());
+    xsm.step(1);
+    assert!(xsm.is_exception_edge());
+    assert!(!xsm.is_user_mode());
+    assert_eq!(xsm.get_regs().ec, XSMWord::Int(2));
+    assert_eq!(xsm.get_regs().ema, XSMWord::Int(5000));
+}
+
+#[test]
+fn user_mode_reads_go_through_the_page_table() {
+    // Virtual pages 0 and 1 are in physical pages 60 and 61.
+    let mut xsm = spawn(
+        "paging",
+        json!({ "states": [
+            {
+                "mode": "U", "ip": 10, "instr": "MOV R0, 1",
+                "regs": { "PTBR": "29696", "PTLR": "2" },
+                "mem": {
+                    "58": { "0": "60", "1": "0100", "2": "61", "3": "0110" },
+                    "60": { "8": "NOP", "10": "MOV R0, 1", "12": "HALT" },
+                },
+            },
+            { "mode": "U", "ip": 12, "instr": "HALT" },
+        ] }),
+    );
+    assert!(xsm.is_user_mode());
+    let page_table = xsm.get_page_table();
+    assert_eq!(page_table.len(), 2);
+    assert_eq!(page_table[0].phy, XSMWord::Int(60));
+    // Aux bits keep their leading zero.
+    assert_eq!(page_table[1].aux, XSMWord::Str("0110".to_owned()));
+    let words: Vec<String> = xsm
+        .read_mem_range_vir(8, 14)
+        .iter()
+        .map(|word| word.to_string())
+        .collect();
+    assert_eq!(words, ["NOP", "", "MOV R0, 1", "", "HALT", ""]);
+
+    let (start, ip, code) = xsm.get_code(4);
+    assert_eq!(ip, 10);
+    assert_eq!(code[(ip - start) / 2], "MOV R0, 1");
+}
+
+#[test]
+fn invalid_ptbr_is_reported_once() {
+    let mut xsm = spawn(
+        "bad-ptbr",
+        json!({ "states": [
+            { "mode": "K", "ip": 0, "instr": "NOP", "regs": { "PTBR": "x" } },
+            { "mode": "K", "ip": 2, "instr": "NOP" },
+            { "mode": "K", "ip": 4, "instr": "NOP", "regs": { "PTBR": "y" } },
+            { "mode": "K", "ip": 6, "instr": "HALT" },
+        ] }),
+    );
+    xsm.step(1);
+    assert!(matches!(xsm.get_errors(), [XSMError::PTBRInvalid(_)]));
+    // Another value is another error.
+    xsm.step(1);
+    assert_eq!(xsm.get_errors().len(), 2);
+}
+
+#[test]
+fn code_not_paged_is_reported_once() {
+    let mut xsm = spawn(
+        "not-paged",
+        json!({ "states": [
+            {
+                "mode": "U", "ip": 10, "instr": "NOP",
+                "regs": { "PTBR": "29696", "PTLR": "1" },
+                "mem": { "58": { "0": "-1", "1": "0000" } },
+            },
+            { "mode": "U", "ip": 12, "instr": "HALT" },
+        ] }),
+    );
+    xsm.get_code(4);
+    xsm.get_code(4);
+    assert!(matches!(
+        xsm.get_errors(),
+        [XSMError::IPNotPaged { ip: 10, page: 0 }]
+    ));
+    xsm.clear_errors();
+    xsm.get_code(4);
+    assert!(xsm.get_errors().is_empty());
+    // Another IP is another error.
+    xsm.step(1);
+    xsm.get_code(4);
+    assert!(matches!(
+        xsm.get_errors(),
+        [XSMError::IPNotPaged { ip: 12, page: 0 }]
+    ));
+}
+
+#[test]
+fn sessions_side_by_side_read_their_own_memory() {
+    let session = |name, word| {
+        spawn(
+            name,
+            json!({ "states": [
+                { "mode": "K", "ip": 0, "instr": "NOP", "mem": { "1": { "0": word } } },
+            ] }),
+        )
+    };
+    let mut a = session("side-a", "a");
+    let mut b = session("side-b", "b");
+    assert_eq!(b.read_mem_range(512, 513), [XSMWord::Str("b".to_owned())]);
+    assert_eq!(a.read_mem_range(512, 513), [XSMWord::Str("a".to_owned())]);
+    // The dumps are written to each session's own directory.
+    assert!(!std::path::Path::new("mem").exists());
+}
+
+#[test]
+fn memory_is_read_again_after_a_step() {
+    let mut xsm = spawn(
+        "cache",
+        json!({ "states": [
+            { "mode": "K", "ip": 0, "instr": "NOP", "mem": { "1": { "0": "a" } } },
+            { "mode": "K", "ip": 2, "instr": "NOP", "mem": { "1": { "0": "b" } } },
+        ] }),
+    );
+    let a = [XSMWord::Str("a".to_owned())];
+    assert_eq!(xsm.read_mem_range(512, 513), a);
+    assert_eq!(xsm.read_mem_range(512, 513), a);
+    assert_eq!(xsm.step(1), 1);
+    assert_eq!(xsm.read_mem_range(512, 513), [XSMWord::Str("b".to_owned())]);
+}
+
+#[test]
+fn past_memory_not_read_at_the_time_is_replayed() {
+    let mut xsm = spawn(
+        "past-mem",
+        json!({ "states": [
+            { "mode": "K", "ip": 0, "instr": "NOP", "mem": { "1": { "0": "a" } } },
+            { "mode": "K", "ip": 2, "instr": "NOP", "mem": { "1": { "0": "b" } } },
+            { "mode": "K", "ip": 4, "instr": "NOP", "mem": { "1": { "0": "c" } } },
+        ] }),
+    );
+    xsm.step(1);
+    xsm.step(1);
+    xsm.set_history_pos(1);
+    assert_eq!(xsm.read_mem_range(512, 513), [XSMWord::Str("b".to_owned())]);
+    xsm.set_history_pos(0);
+    assert_eq!(xsm.read_mem_range(512, 513), [XSMWord::Str("a".to_owned())]);
+    assert!(xsm.get_errors().is_empty());
+    xsm.go_live();
+    assert_eq!(xsm.read_mem_range(512, 513), [XSMWord::Str("c".to_owned())]);
+}
+
+#[test]
+fn run_stops_at_brkp() {
+    let mut xsm = spawn(
+        "run",
+        json!({ "states": [
+            { "mode": "K", "ip": 0, "instr": "NOP" },
+            { "mode": "K", "ip": 2, "instr": "BRKP" },
+            { "mode": "K", "ip": 4, "instr": "NOP", "brkp": true },
+            { "mode": "K", "ip": 6, "instr": "HALT" },
+        ] }),
+    );
+    xsm.run();
+    let start = Instant::now();
+    while !xsm.poll() {
+        assert!(start.elapsed() < Duration::from_secs(5), "run did not stop");
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    assert!(!xsm.is_halted());
+    assert_eq!(xsm.get_regs().ip, XSMWord::Int(4));
+    assert!(!xsm.is_step_count_exact());
+}
+
+#[test]
+fn watchpoint_hit_is_the_address_written() {
+    let mut xsm = spawn(
+        "watch",
+        json!({ "states": [
+            { "mode": "K", "ip": 0, "instr": "MOV [20483], 5" },
+            { "mode": "K", "ip": 2, "instr": "MOV [20484], 7", "mem": { "40": { "3": "5" } } },
+            { "mode": "K", "ip": 4, "instr": "MOV [20484], 7", "mem": { "40": { "4": "7" } } },
+            { "mode": "K", "ip": 6, "instr": "HALT", "mem": { "40": { "4": "7" } } },
+        ] }),
+    );
+    xsm.add_watchpoint(20483);
+    xsm.add_watchpoint(20484);
+    let hits = |xsm: &XSM| -> Vec<usize> { xsm.get_watchpoint_hits().map(|wp| wp.addr).collect() };
+
+    xsm.step(10);
+    assert!(xsm.is_watch_triggered());
+    assert_eq!(xsm.get_step_count(), 1);
+    assert_eq!(hits(&xsm), [20483]);
+    xsm.step(10);
+    assert_eq!(xsm.get_step_count(), 2);
+    assert_eq!(hits(&xsm), [20484]);
+    // Writing the same value again is only told apart by the address xsm reports.
+    xsm.step(10);
+    assert!(xsm.is_watch_triggered());
+    assert_eq!(xsm.get_step_count(), 3);
+    assert_eq!(hits(&xsm), [20484]);
+}
+
+#[test]
+fn ignore_count_is_used_up_by_hits_whose_condition_holds() {
+    let mut xsm = spawn(
+        "ignore",
+        json!({ "states": [
+            { "mode": "K", "ip": 0, "instr": "INR R0" },
+            { "mode": "K", "ip": 2, "instr": "JMP 0" },
+            { "mode": "K", "ip": 0, "instr": "INR R0", "regs": { "R0": "1" } },
+            { "mode": "K", "ip": 2, "instr": "JMP 0" },
+            { "mode": "K", "ip": 0, "instr": "INR R0", "regs": { "R0": "2" } },
+            { "mode": "K", "ip": 2, "instr": "JMP 0" },
+            { "mode": "K", "ip": 4, "instr": "HALT" },
+        ] }),
+    );
+    let bp = xsm.add_breakpoint(2, false);
+    xsm.set_breakpoint_condition(bp, "R0 >= 1").unwrap();
+    xsm.set_breakpoint_ignore_count(bp, 1);
+    assert_eq!(xsm.step(10), 5);
+    assert_eq!(xsm.get_breakpoint_hit().map(|bp| bp.hits), Some(2));
+    assert_eq!(xsm.get_breakpoints()[bp].ignore_count, 0);
+}
+
+#[test]
+fn exit_before_debug_mode_is_an_error() {
+    let argv = [
+        env!("CARGO_BIN_EXE_mock_xsm").to_owned(),
+        "--debug".to_owned(),
+        "/nonexistent/script.json".to_owned(),
+    ];
+    match XSM::spawn_new(&argv) {
+        Err(BackendError::NotInDebugMode { output, .. }) => assert!(!output.is_empty()),
+        Err(e) => panic!("Unexpected error: {}", e),
+        Ok(_) => panic!("mock_xsm without a script entered debug mode"),
+    }
+}
+
+#[test]
+fn missing_binary_is_an_error() {
+    let argv = ["/nonexistent/xsm".to_owned(), "--debug".to_owned()];
+    match XSM::spawn_new(&argv) {
+        Err(BackendError::NotFound { program }) => assert_eq!(program, "/nonexistent/xsm"),
+        Err(e) => panic!("Unexpected error: {}", e),
+        Ok(_) => panic!("a missing binary was spawned"),
+    }
+}
+
+#[test]
+fn command_line_splits_back_into_its_arguments() {
+    let argv = [
+        "/opt/xsm dir/xsm".to_owned(),
+        "--timer".to_owned(),
+        "it's".to_owned(),
+        String::new(),
+    ];
+    let line = process::command_line(&argv);
+    assert_eq!(process::split_command_line(&line), argv);
+    assert_eq!(
+        process::split_command_line(r#"  xsm "a b"\ c  'd' "#),
+        ["xsm", "a b c", "d"]
+    );
+}
+
+#[test]
+fn restart_starts_afresh_and_keeps_breakpoints() {
+    let mut xsm = spawn(
+        "restart",
+        json!({ "states": [
+            { "mode": "K", "ip": 0, "instr": "INI" },
+            { "mode": "K", "ip": 2, "instr": "OUT" },
+            { "mode": "K", "ip": 4, "instr": "NOP", "output": ["hi"] },
+            { "mode": "K", "ip": 6, "instr": "HALT" },
+        ] }),
+    );
+    xsm.add_breakpoint(4, false);
+    xsm.write_input("hi");
+    xsm.step(5);
+    assert_eq!(xsm.get_step_count(), 2);
+    assert_eq!(xsm.get_output(), ["hi"]);
+    xsm.write_input("late");
+    assert_eq!(xsm.get_errors().len(), 1);
+
+    xsm.restart(None, true);
+    assert_eq!(xsm.get_step_count(), 0);
+    assert!(xsm.get_output().is_empty());
+    assert!(xsm.get_errors().is_empty());
+    assert_eq!(xsm.get_breakpoints().len(), 1);
+    assert_eq!(xsm.get_breakpoints()[0].hits, 0);
+    // The input of the old session is written again.
+    assert_eq!(xsm.get_inputs(), [(0, "hi".to_owned())]);
+    assert_eq!(xsm.step(5), 2);
+}
+
+#[test]
+fn goto_past_the_history_is_refused_while_tracing() {
+    let mut xsm = spawn(
+        "goto-trace",
+        json!({ "states": [
+            { "mode": "K", "ip": 0, "instr": "NOP" },
+            { "mode": "K", "ip": 2, "instr": "NOP" },
+            { "mode": "K", "ip": 4, "instr": "NOP" },
+            { "mode": "K", "ip": 6, "instr": "HALT" },
+        ] }),
+    );
+    xsm.set_history_capacity(1);
+    xsm.step(3);
+    let trace_path = std::env::temp_dir().join(format!("xdb-goto-{}.jsonl", std::process::id()));
+    xsm.start_trace(&trace_path).unwrap();
+    xsm.goto_step(1);
+    assert!(matches!(xsm.get_errors(), [XSMError::GotoWhileTracing]));
+    assert_eq!(xsm.get_step_count(), 3);
+    xsm.stop_trace();
+    std::fs::remove_file(&trace_path).unwrap();
+
+    xsm.clear_errors();
+    xsm.goto_step(1);
+    assert!(xsm.get_errors().is_empty());
+    assert_eq!(xsm.get_step_count(), 1);
+    assert_eq!(xsm.get_regs().ip, XSMWord::Int(2));
+}
+
+#[test]
+fn slow_step_times_out_and_its_response_is_skipped() {
+    let timeouts = Timeouts {
+        response: Duration::from_millis(200),
+        ..Timeouts::default()
+    };
+    let mut xsm = spawn_with_timeouts(
+        "timeout",
+        json!({ "states": [
+            { "mode": "K", "ip": 0, "instr": "NOP" },
+            { "mode": "K", "ip": 2, "instr": "NOP", "regs": { "R0": "1" }, "delay_ms": 600 },
+            { "mode": "K", "ip": 4, "instr": "HALT", "regs": { "R0": "2" } },
+        ] }),
+        timeouts,
+    );
+    assert_eq!(xsm.step(1), 0);
+    assert!(matches!(
+        xsm.get_errors(),
+        [XSMError::Backend(BackendError::Timeout { .. })]
+    ));
+    assert_eq!(xsm.get_regs().ip, XSMWord::Int(0));
+    assert!(!xsm.is_step_count_exact());
+
+    std::thread::sleep(Duration::from_millis(600));
+    assert_eq!(xsm.step(1), 1);
+    assert_eq!(xsm.get_regs().ip, XSMWord::Int(4));
+    assert_eq!(xsm.get_regs().r[0], XSMWord::Int(2));
+    assert_eq!(xsm.get_errors().len(), 1);
+}
+
+#[test]
+fn worker_step_can_be_cancelled() {
+    // A breakpoint that is never hit makes every instruction a step of its own.
+    let states: Vec<Value> = (0..20)
+        .map(|i| json!({ "mode": "K", "ip": 2 * i, "instr": "NOP", "delay_ms": 50 }))
+        .collect();
+    let xsm = spawn("cancel", json!({ "states": states }));
+    let mut worker = Worker::spawn(xsm);
+    worker.send(Command::SetBreakpoint {
+        addr: 1000,
+        is_virtual: false,
+        condition: String::new(),
+        ignore_count: 0,
+    });
+    worker.send(Command::Step(19));
+    std::thread::sleep(Duration::from_millis(300));
+    worker.update();
+    assert!(worker.is_busy());
+    assert_eq!(worker.get_progress().1, Some(19));
+    worker.cancel();
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while worker.is_busy() && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(10));
+        worker.update();
+    }
+    assert!(!worker.is_busy());
+    let step = worker.snapshot().step_count;
+    assert!(step > 0 && step < 19, "stepped {}", step);
+}
+
+#[test]
+fn worker_stop_interrupts_a_run() {
+    let states: Vec<Value> = (0..100)
+        .map(|i| json!({ "mode": "K", "ip": 2 * i, "instr": "NOP", "delay_ms": 50 }))
+        .collect();
+    let xsm = spawn("stop", json!({ "states": states }));
+    let mut worker = Worker::spawn(xsm);
+    worker.send(Command::Run);
+    std::thread::sleep(Duration::from_millis(300));
+    worker.update();
+    assert!(worker.snapshot().running);
+    worker.send(Command::Stop);
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while worker.snapshot().running && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(10));
+        worker.update();
+    }
+    let snapshot = worker.snapshot();
+    assert!(!snapshot.running);
+    assert!(snapshot.end.is_none());
+    assert!(snapshot.errors.is_empty(), "{:?}", snapshot.errors);
+}
+
+#[test]
+fn worker_rejects_a_breakpoint_with_a_malformed_condition() {
+    let xsm = spawn(
+        "bad-condition",
+        json!({ "states": [
+            { "mode": "K", "ip": 0, "instr": "NOP" },
+            { "mode": "K", "ip": 2, "instr": "HALT" },
+        ] }),
+    );
+    let mut worker = Worker::spawn(xsm);
+    worker.send(Command::SetBreakpoint {
+        addr: 2,
+        is_virtual: false,
+        condition: "R0 ==".to_owned(),
+        ignore_count: 0,
+    });
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while worker.is_busy() && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(10));
+        worker.update();
+    }
+    let snapshot = worker.snapshot();
+    assert!(snapshot.breakpoints.is_empty());
+    assert!(snapshot.bp_error.is_some());
+}
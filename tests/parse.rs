@@ -0,0 +1,148 @@
+//! Parsing of xsm's debug mode output, against fixtures captured
+//! in the format xsm prints.
+
+use xdb::backend::End;
+use xdb::parse::{self, ParseError};
+use xdb::word::XSMWord;
+use xdb::xsm::Mode;
+
+/// Splits a fixture into lines as the reader thread sends them,
+/// line endings included.
+fn lines(text: &str) -> Vec<String> {
+    text.split_inclusive('\n').map(String::from).collect()
+}
+
+fn string(s: &str) -> XSMWord {
+    XSMWord::Str(s.to_owned())
+}
+
+#[test]
+fn status_in_kernel_mode() {
+    let stop = parse::parse_stop(&lines(include_str!("fixtures/status_kernel.txt"))).unwrap();
+    assert_eq!(stop.end, None);
+    assert_eq!(stop.watch_addr, None);
+    assert!(stop.output.is_empty());
+    let status = stop.status.unwrap();
+    assert!(matches!(status.mode, Mode::Kernel));
+    assert_eq!(status.ip, 512);
+    assert_eq!(status.next_instr, "MOV SP, 76800");
+    assert!(status.text.starts_with("Previous instruction at IP = 0"));
+}
+
+#[test]
+fn out_lines_before_the_status_are_output() {
+    let fixture = lines(include_str!("fixtures/status_with_output.txt"));
+    let stop = parse::parse_stop(&fixture).unwrap();
+    assert_eq!(stop.output, ["Hello", "42"]);
+    let status = stop.status.unwrap();
+    assert!(matches!(status.mode, Mode::User));
+    assert_eq!(status.ip, 2060);
+    assert_eq!(status.next_instr, "MOV R0, \"a: b\"");
+}
+
+#[test]
+fn watch_message_is_not_output() {
+    let stop = parse::parse_stop(&lines(include_str!("fixtures/status_watch.txt"))).unwrap();
+    assert_eq!(stop.watch_addr, Some(28000));
+    assert!(stop.output.is_empty());
+    assert_eq!(stop.status.unwrap().ip, 3002);
+}
+
+#[test]
+fn output_starting_with_watch_is_kept() {
+    let fixture = lines(include_str!("fixtures/status_output_like_watch.txt"));
+    let stop = parse::parse_stop(&fixture).unwrap();
+    assert_eq!(stop.watch_addr, None);
+    assert_eq!(
+        stop.output,
+        ["watch out", "Watchpoint at memory location 28000"]
+    );
+}
+
+#[test]
+fn halt_keeps_the_output_before_it() {
+    let fixture = lines(include_str!("fixtures/halt.txt"));
+    let stop = parse::parse_stop(&fixture).unwrap();
+    assert_eq!(stop.end, Some(End::Halted));
+    assert!(stop.status.is_none());
+    assert_eq!(stop.output, ["42", "bye"]);
+}
+
+#[test]
+fn truncated_status_is_an_error() {
+    let fixture = lines(include_str!("fixtures/status_truncated.txt"));
+    assert_eq!(
+        parse::parse_stop(&fixture).unwrap_err(),
+        ParseError::Truncated {
+            expected: 3,
+            found: 2
+        }
+    );
+}
+
+#[test]
+fn unknown_mode_is_an_error() {
+    let error = parse::parse_stop(&lines(include_str!("fixtures/status_bad_mode.txt")));
+    assert_eq!(
+        error.unwrap_err(),
+        ParseError::BadMode("Mode: X".to_owned())
+    );
+}
+
+#[test]
+fn registers_keep_string_values() {
+    let regs = parse::parse_regs(&lines(include_str!("fixtures/regs.txt"))).unwrap();
+    assert_eq!(regs.r[1], XSMWord::Int(15));
+    assert_eq!(regs.r[2], string("hello"));
+    assert_eq!(regs.r[3], string("hello world"));
+    assert_eq!(regs.r[4], XSMWord::Int(-7));
+    assert_eq!(regs.p[0], string(""));
+    assert_eq!(regs.p[1], string("abc"));
+    assert_eq!(regs.ip, XSMWord::Int(2060));
+    assert_eq!(regs.ptbr, XSMWord::Int(29696));
+    assert_eq!(regs.ema, XSMWord::Int(0));
+}
+
+#[test]
+fn truncated_registers_are_an_error() {
+    let error = parse::parse_regs(&lines(include_str!("fixtures/regs_truncated.txt")));
+    assert_eq!(
+        error.unwrap_err(),
+        ParseError::Truncated {
+            expected: 33,
+            found: 15
+        }
+    );
+}
+
+#[test]
+fn unknown_register_is_an_error() {
+    let error = parse::parse_regs(&lines("debug> R0: 1\tXY: 2\t\n"));
+    assert_eq!(
+        error.unwrap_err(),
+        ParseError::BadRegister("XY: 2".to_owned())
+    );
+}
+
+#[test]
+fn mem_page_words() {
+    let words = parse::parse_mem_page(include_str!("fixtures/mem_page.txt")).unwrap();
+    assert_eq!(words.len(), 512);
+    assert_eq!(words[0], string("MOV R0,"));
+    assert_eq!(words[1], string(" 1"));
+    assert_eq!(words[2], string("hello world"));
+    assert_eq!(words[3], string(""));
+    assert_eq!(words[4], XSMWord::Int(-12));
+}
+
+#[test]
+fn truncated_mem_page_is_an_error() {
+    let error = parse::parse_mem_page(include_str!("fixtures/mem_truncated.txt"));
+    assert_eq!(
+        error.unwrap_err(),
+        ParseError::Truncated {
+            expected: 512,
+            found: 10
+        }
+    );
+}